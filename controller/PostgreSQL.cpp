@@ -277,6 +277,13 @@ PostgreSQL::~PostgreSQL()
 	_onlineNotificationThread.join();
 }
 
+// Log records from smeeclient. May be called from any thread.
+static void smeeLogCallback(void *ctx, int level, const char *target, const char *message)
+{
+	static const char *levels[] = { "OFF", "ERROR", "WARN", "INFO", "DEBUG", "TRACE" };
+	fprintf(stderr, "%s %s: %s\n", (level >= 1 && level <= 5) ? levels[level] : "?", target, message);
+}
+
 void PostgreSQL::configureSmee() 
 {
 	const char *TEMPORAL_SCHEME = "ZT_TEMPORAL_SCHEME";
@@ -296,6 +303,11 @@ void PostgreSQL::configureSmee()
 
 	if (scheme != NULL && host != NULL && port != NULL && ns != NULL && task_queue != NULL) {
 		fprintf(stderr, "creating smee client\n");
+#if ZT_DEBUG==1
+		smeeclient::smee_client_set_log_callback(&smeeLogCallback, NULL, 4);
+#else
+		smeeclient::smee_client_set_log_callback(&smeeLogCallback, NULL, 3);
+#endif
 		std::string hostPort = std::string(scheme) + std::string("://") + std::string(host) + std::string(":") + std::string(port);
 		this->_smee = smeeclient::smee_client_new(hostPort.c_str(), ns, task_queue);
 	} else {
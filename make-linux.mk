@@ -295,10 +295,17 @@ endif
 ifeq ($(ZT_SSO_SUPPORTED), 1)
 	ifeq ($(ZT_EMBEDDED),)
 		override DEFS+=-DZT_SSO_SUPPORTED=1
+		# ZT_SSO_RUSTLS=1 builds zeroidc with rustls instead of OpenSSL
+		ifeq ($(ZT_SSO_RUSTLS),1)
+			ZEROIDC_CARGO_FLAGS=--no-default-features --features rustls
+			ZEROIDC_LIBS=-ldl
+		else
+			ZEROIDC_LIBS=-ldl -lssl -lcrypto
+		endif
 		ifeq ($(ZT_DEBUG),1)
-			LDLIBS+=rustybits/target/debug/libzeroidc.a -ldl -lssl -lcrypto
+			LDLIBS+=rustybits/target/debug/libzeroidc.a $(ZEROIDC_LIBS)
 		else
-			LDLIBS+=rustybits/target/release/libzeroidc.a -ldl -lssl -lcrypto
+			LDLIBS+=rustybits/target/release/libzeroidc.a $(ZEROIDC_LIBS)
 		endif
 	endif
 endif
@@ -438,7 +445,7 @@ debug:	FORCE
 ifeq ($(ZT_SSO_SUPPORTED), 1)
 ifeq ($(ZT_EMBEDDED),)
 zeroidc:	FORCE
-	export PATH=/${HOME}/.cargo/bin:$$PATH; cd rustybits && cargo build $(ZT_CARGO_FLAGS) -p zeroidc
+	export PATH=/${HOME}/.cargo/bin:$$PATH; cd rustybits && cargo build $(ZT_CARGO_FLAGS) $(ZEROIDC_CARGO_FLAGS) -p zeroidc
 endif
 else
 zeroidc:
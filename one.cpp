@@ -824,6 +824,28 @@ static int cli(int argc,char **argv)
 										printf("    AUTH OK, expires in: %lld seconds" ZT_EOL_S, expiresIn);
 									}
 								}
+								nlohmann::json &ssoStatus = n["ssoStatus"];
+								if (ssoStatus.is_object()) {
+									printf("    SSO session: %s", OSUtils::jsonString(ssoStatus["state"], "-").c_str());
+									if (ssoStatus["subject"].is_string()) {
+										printf(", subject: %s", ssoStatus["subject"].get<std::string>().c_str());
+									}
+									if (ssoStatus["last_error"].is_string()) {
+										printf(", last error: %s", ssoStatus["last_error"].get<std::string>().c_str());
+									}
+									printf(ZT_EOL_S);
+								}
+								nlohmann::json &ssoClaims = n["ssoClaims"];
+								if (ssoClaims.is_object()) {
+									std::string who = OSUtils::jsonString(ssoClaims["email"], "");
+									if (who.empty()) {
+										who = OSUtils::jsonString(ssoClaims["preferred_username"], "");
+									}
+									if (who.empty()) {
+										who = OSUtils::jsonString(ssoClaims["subject"], "-");
+									}
+									printf("    Signed in as: %s" ZT_EOL_S, who.c_str());
+								}
 							}
 						}
 					}
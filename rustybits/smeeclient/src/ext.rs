@@ -10,8 +10,11 @@
  * of this software will be governed by version 2.0 of the Apache License.
  */
 
-use std::ffi::CStr;
-use std::os::raw::c_char;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Arc;
+
+use tracing::error;
 
 use crate::NetworkJoinedParams;
 use crate::SmeeClient;
@@ -22,6 +25,8 @@ pub extern "C" fn smee_client_new(
     namespace: *const c_char,
     task_queue: *const c_char,
 ) -> *mut SmeeClient {
+    ztlog::init();
+
     let url = unsafe {
         assert!(!temporal_url.is_null());
         CStr::from_ptr(temporal_url).to_str().unwrap()
@@ -40,7 +45,7 @@ pub extern "C" fn smee_client_new(
     match SmeeClient::new(url, ns, tq) {
         Ok(c) => Box::into_raw(Box::new(c)),
         Err(e) => {
-            println!("error creating smee client instance: {}", e);
+            error!("error creating smee client instance: {}", e);
             std::ptr::null_mut()
         }
     }
@@ -85,8 +90,52 @@ pub extern "C" fn smee_client_notify_network_joined(
     match smee.notify_network_joined(params) {
         Ok(()) => true,
         Err(e) => {
-            println!("error notifying network joined: {0}", e.to_string());
+            error!("error notifying network joined: {}", e);
             false
         }
     }
 }
+
+/// Called with the context pointer given to `smee_client_set_log_callback`, the level (1
+/// for error through 5 for trace), the module the record comes from, and the message
+/// with secrets already redacted.  The strings are only valid during the call.  May be
+/// called from any thread.
+pub type SmeeLogCallback =
+    Option<extern "C" fn(ctx: *mut c_void, level: c_int, target: *const c_char, message: *const c_char)>;
+
+/// The host's context pointer.  The host promises it stays valid, from any thread,
+/// until the callback is replaced or removed.
+struct CallbackContext(*mut c_void);
+unsafe impl Send for CallbackContext {}
+unsafe impl Sync for CallbackContext {}
+
+impl CallbackContext {
+    fn ptr(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// Routes smeeclient's log records up to `max_level` to `callback`, or drops them if
+/// `callback` is null.  A `max_level` of 0 turns logging off.  Once this returns the
+/// previous callback won't be called again.
+#[no_mangle]
+pub extern "C" fn smee_client_set_log_callback(callback: SmeeLogCallback, ctx: *mut c_void, max_level: c_int) {
+    let max_level = ztlog::level_filter_from_int(max_level);
+    let callback = match callback {
+        Some(callback) => callback,
+        None => {
+            ztlog::set_sink(None, max_level);
+            return;
+        }
+    };
+
+    let ctx = CallbackContext(ctx);
+    ztlog::set_sink(
+        Some(Arc::new(move |level, target, message| {
+            let target = CString::new(target).unwrap_or_default();
+            let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+            callback(ctx.ptr(), ztlog::level_to_int(level), target.as_ptr(), message.as_ptr());
+        })),
+        max_level,
+    );
+}
@@ -17,6 +17,7 @@ use std::str::FromStr;
 use std::time::Duration;
 use temporal_client::{Client, ClientOptionsBuilder, RetryClient, WorkflowClientTrait, WorkflowOptions};
 use temporal_sdk_core_protos::{coresdk::AsJsonPayloadExt, temporal::api::enums::v1::WorkflowIdReusePolicy};
+use tracing::{debug, info_span};
 use url::Url;
 use uuid::Uuid;
 
@@ -69,7 +70,8 @@ impl SmeeClient {
     }
 
     pub fn notify_network_joined(&self, params: NetworkJoinedParams) -> Result<(), Box<dyn std::error::Error>> {
-        println!("notifying network joined");
+        let _span = info_span!("smee", network = %params.network_id, member = %params.member_id).entered();
+        debug!("notifying network joined");
         let options = WorkflowOptions {
             id_reuse_policy: WorkflowIdReusePolicy::RejectDuplicate,
             execution_timeout: None,
@@ -86,7 +88,7 @@ impl SmeeClient {
         let workflow_id = Uuid::new_v4();
 
         self.tokio_rt.block_on(async {
-            println!("calilng start_workflow");
+            debug!("calling start_workflow");
             self.client
                 .start_workflow(
                     payload,
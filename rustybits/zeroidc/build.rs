@@ -1,6 +1,6 @@
 extern crate cbindgen;
 
-use cbindgen::{Config, Language};
+use cbindgen::{Config, EnumConfig, Language};
 use std::env;
 use std::path::PathBuf;
 
@@ -14,6 +14,7 @@ fn main() {
         language: Language::C,
         cpp_compat: true,
         namespace: Some(String::from("zeroidc")),
+        enumeration: EnumConfig { prefix_with_name: true, ..Default::default() },
         ..Default::default()
     };
 
@@ -0,0 +1,114 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Central's answers to the ID tokens we post to its `auth_endpoint`.
+//!
+//! On success central may say which network and member it authorized and until when,
+//! e.g.
+//!
+//! ```json
+//! {
+//!     "networkId": "8056c2e21c000001",
+//!     "memberId": "a1b2c3d4e5",
+//!     "authenticationExpiryTime": 1700000000000
+//! }
+//! ```
+//!
+//! Every field is optional, and an empty body is fine too.  The expiry is in
+//! milliseconds, like `authenticationExpiryTime` in network configs.  If it's earlier
+//! than the ID token's, the session is refreshed ahead of it, as long as it's still to
+//! come and refreshing keeps pushing it back.  Errors may come with
+//! `{ "error": "...", "reason": "..." }`.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CentralAuthResponse {
+    #[serde(default)]
+    pub network_id: Option<String>,
+    #[serde(default)]
+    pub member_id: Option<String>,
+    #[serde(default)]
+    pub authentication_expiry_time: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CentralErrorResponse {
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+impl CentralAuthResponse {
+    /// Parses and checks a success response to a token posted for `network_id`, if we
+    /// know which network it was for.
+    pub fn parse(body: &[u8], network_id: Option<&str>) -> Result<CentralAuthResponse, String> {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(CentralAuthResponse::default());
+        }
+        let res: CentralAuthResponse = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+
+        if let Some(nwid) = res.network_id.as_deref() {
+            if !is_hex_id(nwid, 16) {
+                return Err(format!("invalid network ID {}", nwid));
+            }
+            if let Some(expected) = network_id {
+                if !nwid.eq_ignore_ascii_case(expected) {
+                    return Err(format!("response is for network {}, not {}", nwid, expected));
+                }
+            }
+        }
+        if let Some(member_id) = res.member_id.as_deref() {
+            if !is_hex_id(member_id, 10) {
+                return Err(format!("invalid member ID {}", member_id));
+            }
+        }
+        if res.authentication_expiry_time == Some(0) {
+            return Err("authentication expiry time is 0".to_string());
+        }
+        Ok(res)
+    }
+
+    /// When central's authorization runs out, in seconds since the epoch.
+    pub fn exp_time(&self) -> Option<u64> {
+        self.authentication_expiry_time.map(|ms| ms / 1000)
+    }
+
+    /// The network ID as a number, or 0 if central didn't send one.
+    pub fn network_id_u64(&self) -> u64 {
+        parse_hex_id(self.network_id.as_deref())
+    }
+
+    /// The member ID as a number, or 0 if central didn't send one.
+    pub fn member_id_u64(&self) -> u64 {
+        parse_hex_id(self.member_id.as_deref())
+    }
+}
+
+/// Central's explanation for rejecting a token, if it gave one.
+pub fn error_reason(body: &[u8]) -> Option<String> {
+    let res: CentralErrorResponse = serde_json::from_slice(body).ok()?;
+    match (res.error, res.reason) {
+        (Some(error), Some(reason)) => Some(format!("{}: {}", error, reason)),
+        (error, reason) => reason.or(error),
+    }
+}
+
+fn is_hex_id(id: &str, len: usize) -> bool {
+    id.len() == len && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn parse_hex_id(id: Option<&str>) -> u64 {
+    id.and_then(|id| u64::from_str_radix(id, 16).ok()).unwrap_or(0)
+}
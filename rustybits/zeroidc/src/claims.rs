@@ -0,0 +1,76 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Who the user is logged in as, from the verified ID token.
+//!
+//! The standard profile claims are always kept.  Anything else, like `groups` or
+//! `roles`, has to be named in the provider profile's `claims`, and is passed along as
+//! the IdP sent it.
+
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use openidconnect::core::{CoreIdToken, CoreIdTokenClaims};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct SessionClaims {
+    pub subject: String,
+    pub issuer: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+    /// The extra claims named in the provider profile that the token carried.
+    #[serde(default)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl SessionClaims {
+    /// Collects the claims of `id_token`, which must have been verified already and
+    /// yielded `claims`.
+    pub fn new(id_token: &CoreIdToken, claims: &CoreIdTokenClaims, extra: &[String]) -> SessionClaims {
+        SessionClaims {
+            subject: claims.subject().to_string(),
+            issuer: claims.issuer().to_string(),
+            email: claims.email().map(|e| e.to_string()),
+            email_verified: claims.email_verified(),
+            name: claims.name().and_then(|n| n.get(None)).map(|n| n.to_string()),
+            preferred_username: claims.preferred_username().map(|u| u.to_string()),
+            extra: if extra.is_empty() {
+                BTreeMap::new()
+            } else {
+                extra_claims(id_token, extra)
+            },
+        }
+    }
+}
+
+/// Picks `names` out of the token's payload.  openidconnect drops claims it doesn't
+/// know while parsing, so they're read from the JWT again.
+pub(crate) fn extra_claims(id_token: &CoreIdToken, names: &[String]) -> BTreeMap<String, Value> {
+    let jwt = id_token.to_string();
+    let payload = jwt
+        .split('.')
+        .nth(1)
+        .and_then(|p| BASE64URL.decode(p).ok())
+        .and_then(|p| serde_json::from_slice::<Value>(&p).ok());
+    let mut payload = match payload {
+        Some(Value::Object(p)) => p,
+        _ => return BTreeMap::new(),
+    };
+    names
+        .iter()
+        .filter_map(|name| payload.remove(name).map(|v| (name.clone(), v)))
+        .collect()
+}
@@ -0,0 +1,268 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Authenticating to the token endpoint as a confidential client.
+//!
+//! Public clients only send their `client_id`.  A client can also be given a secret,
+//! sent with HTTP Basic auth (`client_secret_basic`) or in the request body
+//! (`client_secret_post`), or a private key that signs a short-lived JWT for every
+//! request (`private_key_jwt`, RFC 7523).  Unless the provider profile names a method,
+//! the strongest one that both the provider's `token_endpoint_auth_methods_supported`
+//! and our credentials allow is used.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64URL};
+use base64::Engine;
+use openidconnect::core::{
+    CoreClientAuthMethod, CoreEdDsaPrivateSigningKey, CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey,
+};
+use openidconnect::http::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use openidconnect::http::Method;
+use openidconnect::{ClientSecret, HttpRequest, JsonWebKeyId, PrivateSigningKey};
+use rand::RngCore;
+use serde_json::json;
+use tracing::warn;
+use url::Url;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::ClientAuthError;
+use crate::profile::TokenEndpointAuthMethod;
+use crate::secret::Secret;
+
+/// `client_assertion_type` for a `private_key_jwt` assertion.
+pub const JWT_BEARER_ASSERTION: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+/// How long an assertion is valid for, in seconds.
+const ASSERTION_LIFETIME: u64 = 60;
+
+/// A private key for `private_key_jwt`.  RSA keys sign with RS256, Ed25519 keys with
+/// EdDSA.
+pub struct ClientKey {
+    key: SigningKey,
+    kid: Option<String>,
+}
+
+enum SigningKey {
+    Rsa(CoreRsaPrivateSigningKey),
+    Ed25519(CoreEdDsaPrivateSigningKey),
+}
+
+impl ClientKey {
+    /// Reads a PKCS#1 RSA key (`BEGIN RSA PRIVATE KEY`) or a PKCS#8 Ed25519 key
+    /// (`BEGIN PRIVATE KEY`).  `kid` is sent in the assertion's header so the IdP can
+    /// tell which of the client's registered keys to check it with.
+    pub fn from_pem(pem: &str, kid: Option<&str>) -> Result<ClientKey, ClientAuthError> {
+        let jwk_kid = kid.map(|k| JsonWebKeyId::new(k.to_string()));
+        let key = match CoreRsaPrivateSigningKey::from_pem(pem, jwk_kid.clone()) {
+            Ok(k) => SigningKey::Rsa(k),
+            Err(rsa_err) => match CoreEdDsaPrivateSigningKey::from_ed25519_pem(pem, jwk_kid) {
+                Ok(k) => SigningKey::Ed25519(k),
+                Err(ed_err) => {
+                    return Err(ClientAuthError::InvalidKey(format!(
+                        "not an RSA key ({}) or an Ed25519 key ({})",
+                        rsa_err, ed_err
+                    )))
+                }
+            },
+        };
+        Ok(ClientKey { key, kid: kid.map(str::to_string) })
+    }
+
+    pub fn from_file(path: &Path, kid: Option<&str>) -> Result<ClientKey, ClientAuthError> {
+        let pem = Zeroizing::new(fs::read_to_string(path)?);
+        ClientKey::from_pem(&pem, kid)
+    }
+
+    fn alg(&self) -> CoreJwsSigningAlgorithm {
+        match self.key {
+            SigningKey::Rsa(_) => CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            SigningKey::Ed25519(_) => CoreJwsSigningAlgorithm::EdDsaEd25519,
+        }
+    }
+
+    /// Signs `claims` as a compact JWS.
+    fn sign(&self, claims: &serde_json::Value) -> Result<String, ClientAuthError> {
+        let alg = self.alg();
+        let mut header = json!({ "alg": alg, "typ": "JWT" });
+        if let Some(kid) = self.kid.as_ref() {
+            header["kid"] = json!(kid);
+        }
+
+        let mut jws = format!(
+            "{}.{}",
+            BASE64URL.encode(header.to_string()),
+            BASE64URL.encode(claims.to_string())
+        );
+        let signature = match &self.key {
+            SigningKey::Rsa(k) => k.sign(&alg, jws.as_bytes()),
+            SigningKey::Ed25519(k) => k.sign(&alg, jws.as_bytes()),
+        }
+        .map_err(|e| ClientAuthError::Signing(e.to_string()))?;
+        jws.push('.');
+        jws.push_str(&BASE64URL.encode(signature));
+        Ok(jws)
+    }
+}
+
+/// The client's own credentials and how they're presented to the token endpoint.
+#[derive(Clone, Default)]
+pub(crate) struct ClientAuth {
+    pub method: TokenEndpointAuthMethod,
+    pub secret: Option<Secret>,
+    pub key: Option<Arc<ClientKey>>,
+    pub client_id: String,
+    /// Who an assertion is addressed to: the token endpoint.
+    pub audience: String,
+}
+
+impl ClientAuth {
+    /// Picks the method to use.  `preferred` comes from the provider profile and wins
+    /// if set.  A provider that doesn't list its methods supports only
+    /// `client_secret_basic` (OpenID Connect Discovery 1.0, section 3).
+    pub fn choose_method(
+        &mut self,
+        supported: Option<&Vec<CoreClientAuthMethod>>,
+        preferred: Option<TokenEndpointAuthMethod>,
+    ) {
+        if let Some(method) = preferred {
+            self.method = method;
+            return;
+        }
+
+        let default = [CoreClientAuthMethod::ClientSecretBasic];
+        let supported = supported.map(|s| s.as_slice()).unwrap_or(&default);
+        let offers = |m: &CoreClientAuthMethod| supported.contains(m);
+
+        self.method = if self.key.is_some() && offers(&CoreClientAuthMethod::PrivateKeyJwt) {
+            TokenEndpointAuthMethod::PrivateKeyJwt
+        } else if self.secret.is_some() && offers(&CoreClientAuthMethod::ClientSecretBasic) {
+            TokenEndpointAuthMethod::ClientSecretBasic
+        } else if self.secret.is_some() && offers(&CoreClientAuthMethod::ClientSecretPost) {
+            TokenEndpointAuthMethod::ClientSecretPost
+        } else {
+            if self.secret.is_some() || self.key.is_some() {
+                warn!(
+                    "identity provider supports none of our client authentication methods. acting as a public client"
+                );
+            }
+            TokenEndpointAuthMethod::None
+        };
+    }
+
+    /// The secret to build the client with, if the chosen method sends one.
+    pub fn client_secret(&self) -> Option<ClientSecret> {
+        match self.method {
+            TokenEndpointAuthMethod::ClientSecretBasic | TokenEndpointAuthMethod::ClientSecretPost => {
+                self.secret.as_ref().map(|s| ClientSecret::new(s.expose().to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    /// A fresh `client_assertion` for the next request, if the chosen method needs
+    /// one.
+    pub fn assertion(&self) -> Result<Option<Secret>, ClientAuthError> {
+        if self.method != TokenEndpointAuthMethod::PrivateKeyJwt {
+            return Ok(None);
+        }
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| ClientAuthError::InvalidKey("private_key_jwt configured without a key".to_string()))?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut jti = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut jti);
+        let claims = json!({
+            "iss": self.client_id,
+            "sub": self.client_id,
+            "aud": self.audience,
+            "jti": BASE64URL.encode(jti),
+            "iat": now,
+            "exp": now + ASSERTION_LIFETIME,
+        });
+        key.sign(&claims).map(|a| Some(Secret::new(a)))
+    }
+
+    /// A form POST to `url` authenticated the same way as token requests, for endpoints
+    /// the oauth2 crate has no request type for.
+    pub fn form_request(&self, url: Url, mut params: Vec<(String, String)>) -> Result<HttpRequest, ClientAuthError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        if !params.iter().any(|(k, _)| k == "client_id") {
+            params.push(("client_id".to_string(), self.client_id.clone()));
+        }
+
+        match (self.method, self.secret.as_ref()) {
+            (TokenEndpointAuthMethod::ClientSecretBasic, Some(secret)) => {
+                // RFC 6749 section 2.3.1 form-encodes both halves
+                let encode = |s: &str| url::form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+                let credentials = Zeroizing::new(format!("{}:{}", encode(&self.client_id), encode(secret.expose())));
+                let value = Zeroizing::new(format!("Basic {}", BASE64.encode(credentials.as_bytes())));
+                let mut value = HeaderValue::from_str(&value).expect("base64 is a valid header value");
+                value.set_sensitive(true);
+                headers.insert(AUTHORIZATION, value);
+            }
+            (TokenEndpointAuthMethod::ClientSecretPost, Some(secret)) => {
+                params.push(("client_secret".to_string(), secret.expose().to_string()));
+            }
+            _ => {}
+        }
+        if let Some(assertion) = self.assertion()? {
+            params.push(("client_assertion_type".to_string(), JWT_BEARER_ASSERTION.to_string()));
+            params.push(("client_assertion".to_string(), assertion.expose().to_string()));
+        }
+
+        let mut body = url::form_urlencoded::Serializer::new(String::new());
+        body.extend_pairs(params.iter());
+        for (_, value) in params.iter_mut() {
+            value.zeroize();
+        }
+        Ok(HttpRequest {
+            url,
+            method: Method::POST,
+            headers,
+            body: body.finish().into_bytes(),
+        })
+    }
+
+    /// Puts a new assertion in a form-encoded request built with an earlier one.  The
+    /// device flow sends the same request until the user approves the login, which can
+    /// take longer than an assertion lives.
+    pub fn refresh_assertion(&self, mut req: HttpRequest) -> Result<HttpRequest, ClientAuthError> {
+        let assertion = match self.assertion()? {
+            Some(a) => a,
+            None => return Ok(req),
+        };
+        let mut body = url::form_urlencoded::Serializer::new(String::new());
+        for (name, value) in url::form_urlencoded::parse(&req.body) {
+            match name.as_ref() {
+                "client_assertion" => body.append_pair(&name, assertion.expose()),
+                _ => body.append_pair(&name, &value),
+            };
+        }
+        let body = Zeroizing::new(body.finish());
+        req.body.zeroize();
+        req.body = body.as_bytes().to_vec();
+        Ok(req)
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! OAuth 2.0 Device Authorization Grant (RFC 8628) for nodes without a browser.
+
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+
+use openidconnect::core::CoreDeviceAuthorizationResponse;
+use openidconnect::{HttpRequest, HttpResponse, Nonce};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::client_auth::{ClientAuth, JWT_BEARER_ASSERTION};
+use crate::dpop::{self, DpopKey};
+use crate::error::SSOExchangeError;
+use crate::events::ZeroIDCEvent;
+use crate::http::HttpClient;
+use crate::profile::TokenEndpointAuthMethod;
+use crate::state::SessionState;
+use crate::{post_token_to_central, verify_token_response, Inner, ZeroIDC};
+
+/// What the user needs to finish a device login on another machine.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceAuthorization {
+    pub user_code: String,
+    pub verification_uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    pub interval: u64,
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+impl ZeroIDC {
+    /// Requests a device code from the IdP and starts polling the token endpoint in the
+    /// background.  Once the user approves the login, the ID token is posted to central
+    /// and the refresh thread is started, same as after `do_token_exchange`.
+    /// `set_nonce_and_csrf` must have been called first, since central wants its state
+    /// back along with the ID token.
+    pub fn start_device_flow(&mut self) -> Result<DeviceAuthorization, SSOExchangeError> {
+        let (http, client, client_auth, dpop, scopes) = {
+            let mut i = self.inner.lock().unwrap();
+            if i.state.is_running() || i.device_pending || i.loopback_pending {
+                return Err(SSOExchangeError::InvalidState(
+                    "authentication already in progress".to_string(),
+                ));
+            }
+
+            let client = match i.oidc_client.clone() {
+                Some(c) => c,
+                None => return Err(SSOExchangeError::InvalidState("no oidc client".to_string())),
+            };
+            if i.network_id().is_none() {
+                return Err(SSOExchangeError::InvalidState("no state set".to_string()));
+            }
+            i.device_pending = true;
+            i.state = SessionState::AwaitingAuthorization;
+            (
+                i.http.clone(),
+                client,
+                i.client_auth.clone(),
+                i.dpop.clone(),
+                i.profile.scopes(),
+            )
+        };
+
+        let details: Result<CoreDeviceAuthorizationResponse, SSOExchangeError> = client
+            .exchange_device_code()
+            .map_err(|e| SSOExchangeError::InvalidState(format!("device authorization not supported: {}", e)))
+            .and_then(|req| {
+                let assertion = client_auth.assertion()?;
+                let mut req = req.add_scopes(scopes);
+                if let Some(a) = assertion.as_ref() {
+                    req = req
+                        .add_extra_param("client_assertion_type", JWT_BEARER_ASSERTION)
+                        .add_extra_param("client_assertion", a.expose());
+                }
+                req.request(|req| http.request(req))
+                    .map_err(SSOExchangeError::from_token_error)
+            });
+
+        let details = match details {
+            Ok(d) => d,
+            Err(e) => {
+                let mut i = self.inner.lock().unwrap();
+                i.device_pending = false;
+                i.state = SessionState::Idle;
+                return Err(e);
+            }
+        };
+
+        let auth = DeviceAuthorization {
+            user_code: details.user_code().secret().to_string(),
+            verification_uri: details.verification_uri().to_string(),
+            verification_uri_complete: details.verification_uri_complete().map(|u| u.secret().to_string()),
+            expires_in: details.expires_in().as_secs(),
+            interval: details.interval().as_secs(),
+        };
+
+        let inner = Arc::clone(&self.inner);
+        let span = self.span();
+        spawn(move || span.in_scope(|| poll_device_token(inner, http, client, client_auth, dpop, details)));
+
+        Ok(auth)
+    }
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn poll_device_token(
+    inner: Arc<Mutex<Inner>>,
+    http: HttpClient,
+    client: openidconnect::core::CoreClient,
+    client_auth: ClientAuth,
+    dpop: Option<Arc<DpopKey>>,
+    details: CoreDeviceAuthorizationResponse,
+) {
+    // The oauth2 crate takes care of the polling interval, `authorization_pending`
+    // and `slow_down`.  We only need a way to bail out if the network is left while
+    // the user still hasn't approved the login, and a new client assertion and DPoP
+    // proof for each poll if we use them.
+    let poll_client = |req: HttpRequest| -> Result<HttpResponse, openidconnect::reqwest::Error<reqwest::Error>> {
+        if !inner.lock().unwrap().device_pending {
+            return Err(openidconnect::reqwest::Error::Other(
+                "device authorization cancelled".to_string(),
+            ));
+        }
+        let req = client_auth
+            .refresh_assertion(req)
+            .map_err(|e| openidconnect::reqwest::Error::Other(e.to_string()))?;
+        dpop::request(dpop.as_deref(), req, |req| http.request(req))
+    };
+
+    let mut req = client.exchange_device_access_token(&details);
+    if client_auth.method == TokenEndpointAuthMethod::PrivateKeyJwt {
+        // replaced by poll_client
+        req = req
+            .add_extra_param("client_assertion_type", JWT_BEARER_ASSERTION)
+            .add_extra_param("client_assertion", "");
+    }
+    let token_response = req.request(poll_client, sleep, None);
+
+    let (outcome, sink) = {
+        let mut i = inner.lock().unwrap();
+        if !i.device_pending {
+            debug!("device authorization cancelled");
+            return;
+        }
+        i.device_pending = false;
+        i.state = SessionState::Exchanging;
+
+        let outcome = match token_response {
+            Ok(res) => {
+                // There is no authorization request carrying our nonce in this flow.
+                let no_nonce = |_: Option<&Nonce>| Ok(());
+                match verify_token_response(&mut i.jwks, res, no_nonce) {
+                    Ok((tok, claims)) => post_token_to_central(&mut i, &tok, &claims)
+                        .map(|_| ())
+                        .map_err(|e| format!("device authorization failed: {}", e)),
+                    Err(e) => {
+                        let reason = format!("device authorization returned an invalid token response: {}", e);
+                        i.last_error = Some(e);
+                        Err(reason)
+                    }
+                }
+            }
+            Err(e) => Err(format!("device token error: {}", e)),
+        };
+
+        if outcome.is_err() {
+            i.state = SessionState::Idle;
+        }
+        (outcome, i.events.clone())
+    };
+
+    let event = match outcome {
+        Ok(()) => {
+            let mut idc = ZeroIDC { inner };
+            idc.start();
+            (ZeroIDCEvent::LoginSucceeded, json!({ "exp_time": idc.get_exp_time() }))
+        }
+        Err(reason) => {
+            warn!("{}", reason);
+            (ZeroIDCEvent::LoginFailed, json!({ "reason": reason }))
+        }
+    };
+    sink.deliver(vec![event]);
+}
@@ -0,0 +1,179 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! DPoP (RFC 9449) proofs that bind a session's tokens to a key held by the node.
+//!
+//! When DPoP is turned on and the IdP advertises ES256 in
+//! `dpop_signing_alg_values_supported`, each session gets a fresh P-256 key.  Every
+//! token request carries a `DPoP` header signed with it, and the authorization URL
+//! carries the key's thumbprint as `dpop_jkt`, so a stolen refresh token is useless
+//! without the key.  The key is saved along with the session it belongs to.
+//!
+//! A server that wants a nonce in our proofs answers with a `use_dpop_nonce` error and a
+//! `DPoP-Nonce` header.  The request is then sent once more with the nonce, which is
+//! remembered for later requests.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use openidconnect::core::CoreTokenType;
+use openidconnect::http::header::HeaderName;
+use openidconnect::http::{HeaderValue, StatusCode};
+use openidconnect::{HttpRequest, HttpResponse};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use zeroize::Zeroizing;
+
+use crate::secret::Secret;
+
+/// The only proof algorithm we sign with.
+pub const DPOP_ALG: &str = "ES256";
+
+const DPOP: &str = "dpop";
+const DPOP_NONCE: &str = "dpop-nonce";
+
+/// A session's proof key, and the last nonce the server gave us for it.
+pub struct DpopKey {
+    key: SigningKey,
+    nonce: Mutex<Option<String>>,
+}
+
+impl DpopKey {
+    pub fn generate() -> DpopKey {
+        DpopKey { key: SigningKey::random(&mut OsRng), nonce: Mutex::new(None) }
+    }
+
+    /// Restores a key saved with [`DpopKey::to_secret`].
+    pub fn from_secret(secret: &Secret) -> Option<DpopKey> {
+        let bytes = Zeroizing::new(BASE64URL.decode(secret.expose()).ok()?);
+        Some(DpopKey {
+            key: SigningKey::from_slice(&bytes).ok()?,
+            nonce: Mutex::new(None),
+        })
+    }
+
+    pub fn to_secret(&self) -> Secret {
+        Secret::new(BASE64URL.encode(self.key.to_bytes()))
+    }
+
+    fn jwk(&self) -> serde_json::Value {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "x": BASE64URL.encode(point.x().expect("uncompressed point")),
+            "y": BASE64URL.encode(point.y().expect("uncompressed point")),
+        })
+    }
+
+    /// The RFC 7638 thumbprint of the public key, sent as `dpop_jkt`.
+    pub fn thumbprint(&self) -> String {
+        // serde_json sorts object keys, which gives the canonical form
+        BASE64URL.encode(Sha256::digest(self.jwk().to_string()))
+    }
+
+    /// A proof for one request.
+    fn proof(&self, req: &HttpRequest) -> String {
+        let mut htu = req.url.clone();
+        htu.set_query(None);
+        htu.set_fragment(None);
+
+        let mut jti = [0u8; 16];
+        OsRng.fill_bytes(&mut jti);
+        let iat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut claims = json!({
+            "jti": BASE64URL.encode(jti),
+            "htm": req.method.as_str(),
+            "htu": htu.as_str(),
+            "iat": iat,
+        });
+        if let Some(nonce) = self.nonce.lock().unwrap().as_ref() {
+            claims["nonce"] = json!(nonce);
+        }
+        let header = json!({ "typ": "dpop+jwt", "alg": DPOP_ALG, "jwk": self.jwk() });
+
+        let mut jws = format!(
+            "{}.{}",
+            BASE64URL.encode(header.to_string()),
+            BASE64URL.encode(claims.to_string())
+        );
+        let signature: Signature = self.key.sign(jws.as_bytes());
+        jws.push('.');
+        jws.push_str(&BASE64URL.encode(signature.to_bytes()));
+        jws
+    }
+
+    fn with_proof(&self, mut req: HttpRequest) -> HttpRequest {
+        let proof = HeaderValue::from_str(&self.proof(&req)).expect("base64url is a valid header value");
+        req.headers.insert(HeaderName::from_static(DPOP), proof);
+        req
+    }
+
+    /// Remembers a nonce sent by the server.  Returns true if it's a new one.
+    fn update_nonce(&self, res: &HttpResponse) -> bool {
+        let nonce = match res.headers.get(DPOP_NONCE).and_then(|v| v.to_str().ok()) {
+            Some(n) => n.to_string(),
+            None => return false,
+        };
+        let mut current = self.nonce.lock().unwrap();
+        if current.as_deref() == Some(nonce.as_str()) {
+            return false;
+        }
+        *current = Some(nonce);
+        true
+    }
+}
+
+/// Whether the IdP bound the tokens it issued to our key rather than issuing bearer
+/// tokens, which it's free to do.
+pub fn is_bound(token_type: &CoreTokenType) -> bool {
+    token_type.as_ref().eq_ignore_ascii_case("dpop")
+}
+
+/// Whether `res` asks us to try again with the server's nonce.
+fn is_nonce_challenge(res: &HttpResponse) -> bool {
+    if res.status_code != StatusCode::BAD_REQUEST && res.status_code != StatusCode::UNAUTHORIZED {
+        return false;
+    }
+    match serde_json::from_slice::<serde_json::Value>(&res.body) {
+        Ok(v) => v["error"] == "use_dpop_nonce",
+        Err(_) => false,
+    }
+}
+
+/// Sends `req` with `send`, adding a proof signed with `key` if there is one.
+pub fn request<E>(
+    key: Option<&DpopKey>,
+    req: HttpRequest,
+    send: impl Fn(HttpRequest) -> Result<HttpResponse, E>,
+) -> Result<HttpResponse, E> {
+    let key = match key {
+        Some(k) => k,
+        None => return send(req),
+    };
+
+    let res = send(key.with_proof(req.clone()))?;
+    if key.update_nonce(&res) && is_nonce_challenge(&res) {
+        debug!("retrying with the server's DPoP nonce");
+        return send(key.with_proof(req));
+    }
+    Ok(res)
+}
@@ -10,6 +10,9 @@
  * of this software will be governed by version 2.0 of the Apache License.
  */
 
+use openidconnect::core::CoreErrorResponseType;
+use openidconnect::{RequestTokenError, StandardErrorResponse};
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -19,16 +22,174 @@ pub enum ZeroIDCError {
 
     #[error(transparent)]
     ParseError(#[from] url::ParseError),
+
+    #[error("error starting loopback listener: {0}")]
+    Listener(#[from] std::io::Error),
+
+    #[error(transparent)]
+    HttpConfig(#[from] HttpConfigError),
 }
 
+/// Errors from logging in to a network: exchanging an authorization or device code
+/// for tokens and handing the ID token to central.
 #[derive(Error, Debug)]
-#[error("SSO Exchange Error: {message:}")]
-pub struct SSOExchangeError {
-    message: String,
+pub enum SSOExchangeError {
+    /// The exchange can't happen in the session's current state, e.g. no login was
+    /// started or the state token is malformed.
+    #[error("{0}")]
+    InvalidState(String),
+
+    #[error(transparent)]
+    Setup(#[from] ZeroIDCError),
+
+    /// The IdP answered with an OAuth error.
+    #[error("identity provider returned {error}{}", .description.as_ref().map(|d| format!(": {}", d)).unwrap_or_default())]
+    Provider { error: String, description: Option<String> },
+
+    /// The IdP couldn't be reached or sent something that isn't an OAuth response.
+    #[error("error talking to identity provider: {0}")]
+    ProviderRequest(String),
+
+    #[error(transparent)]
+    Session(#[from] SessionError),
+
+    #[error(transparent)]
+    ClientAuth(#[from] ClientAuthError),
+
+    #[error("additional license seats required. Please contact your network administrator.")]
+    LicenseRequired,
+
+    #[error("error from central endpoint: HTTP {status}{}", .reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default())]
+    Central { status: u16, reason: Option<String> },
+
+    /// Central accepted the token but its response doesn't make sense.
+    #[error("invalid response from central endpoint: {0}")]
+    InvalidCentralResponse(String),
+
+    #[error("error posting to central endpoint: {0}")]
+    CentralRequest(String),
 }
 
 impl SSOExchangeError {
-    pub fn new(message: String) -> Self {
-        SSOExchangeError { message }
+    /// Converts a failed token or device authorization request.
+    pub fn from_token_error<RE: std::error::Error + 'static>(
+        e: RequestTokenError<RE, StandardErrorResponse<CoreErrorResponseType>>,
+    ) -> Self {
+        match e {
+            RequestTokenError::ServerResponse(r) => SSOExchangeError::Provider {
+                error: r.error().as_ref().to_string(),
+                description: r.error_description().cloned(),
+            },
+            RequestTokenError::Parse(e, body) => match SessionError::from_parse_error(&e, &body) {
+                Some(e) => SSOExchangeError::Session(e),
+                None => SSOExchangeError::ProviderRequest(format!("{}: {}", e, String::from_utf8_lossy(&body))),
+            },
+            e => SSOExchangeError::ProviderRequest(e.to_string()),
+        }
+    }
+}
+
+/// Errors with the client's own credentials for the token endpoint.
+#[derive(Error, Debug)]
+pub enum ClientAuthError {
+    #[error("error reading client key: {0}")]
+    KeyFile(#[from] std::io::Error),
+
+    #[error("invalid client key: {0}")]
+    InvalidKey(String),
+
+    #[error("error signing client assertion: {0}")]
+    Signing(String),
+}
+
+/// Errors setting up the HTTP client.
+#[derive(Error, Debug)]
+pub enum HttpConfigError {
+    #[error("error reading CA file {}: {source}", .path.display())]
+    CaFile { path: PathBuf, source: std::io::Error },
+
+    #[error("error reading client certificate {}: {source}", .path.display())]
+    ClientCertFile { path: PathBuf, source: std::io::Error },
+
+    #[error("client_cert and client_key have to be set together")]
+    IncompleteClientCert,
+
+    #[error("invalid HTTP client configuration: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+/// Errors pushing an authorization request to the IdP (RFC 9126).
+#[derive(Error, Debug)]
+pub enum PushedAuthorizationError {
+    #[error("identity provider returned {error}{}", .description.as_ref().map(|d| format!(": {}", d)).unwrap_or_default())]
+    Provider { error: String, description: Option<String> },
+
+    #[error("error pushing authorization request: {0}")]
+    Request(String),
+
+    #[error(transparent)]
+    ClientAuth(#[from] ClientAuthError),
+}
+
+/// Errors that end an established or pending SSO session.
+#[derive(Error, Debug, Clone)]
+pub enum SessionError {
+    #[error("token response did not include an ID token")]
+    MissingIdToken,
+
+    #[error("ID token verification failed: {0}")]
+    TokenVerification(String),
+
+    #[error("token refresh rejected: {0}")]
+    RefreshRejected(String),
+
+    /// A refresh failed for a reason that may go away, such as the IdP or central
+    /// being unreachable.  It's retried until the ID token expires.
+    #[error("token refresh failed: {0}")]
+    RefreshFailed(String),
+
+    /// The token endpoint sent JSON that isn't a usable token response, such as an
+    /// ID token that isn't a JWT or is missing required claims.
+    #[error("malformed token response: {0}")]
+    MalformedTokenResponse(String),
+
+    #[error("rejected by admission policy: {0}")]
+    PolicyRejected(PolicyViolation),
+}
+
+/// Why the local admission policy refused an ID token.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    #[error("not a member of any required group")]
+    MissingGroup,
+
+    #[error("email address is not verified")]
+    EmailNotVerified,
+
+    #[error("email domain {} is not allowed", .0.as_deref().unwrap_or("(none)"))]
+    EmailDomain(Option<String>),
+
+    #[error("authentication context {} is not allowed", .0.as_deref().unwrap_or("(none)"))]
+    Acr(Option<String>),
+
+    #[error("missing authentication method {0}")]
+    Amr(String),
+
+    #[error("token has no auth_time")]
+    MissingAuthTime,
+
+    /// The user authenticated this many seconds ago.
+    #[error("authenticated {0}s ago, longer than allowed")]
+    AuthTooOld(u64),
+}
+
+impl SessionError {
+    /// Tells a token response with bad contents apart from a body that isn't a token
+    /// response at all, like an error page from a proxy.
+    pub fn from_parse_error(e: &dyn std::fmt::Display, body: &[u8]) -> Option<SessionError> {
+        match serde_json::from_slice::<serde_json::Value>(body) {
+            Ok(v) if v.is_object() => Some(SessionError::MalformedTokenResponse(e.to_string())),
+            _ => None,
+        }
     }
 }
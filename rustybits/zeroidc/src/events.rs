@@ -0,0 +1,66 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Session events reported to the host as they happen, so it doesn't have to poll
+//! `is_running` and `get_exp_time` to find out a user needs to log in again.
+
+use std::sync::{Arc, RwLock};
+
+use serde_json::Value;
+
+/// Things that happen to a session.  Values are part of the FFI and never renumbered.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZeroIDCEvent {
+    /// New tokens were fetched and posted to central.  Detail: `exp_time`.
+    RefreshSucceeded = 0,
+    /// A refresh attempt failed.  Detail: `reason`, `permanent`, and `retry_in` (seconds)
+    /// if it'll be retried.
+    RefreshFailed = 1,
+    /// Refreshing has started failing and the ID token runs out soon.  Detail: `exp_time`.
+    AboutToExpire = 2,
+    /// The ID token ran out before it could be refreshed.  Detail: `exp_time`.
+    Expired = 3,
+    /// The session is over and the user has to log in again.  Detail: `reason`.
+    ReauthRequired = 4,
+    /// A login finished in the background, through the loopback listener or the device
+    /// flow, and the session is running.  Detail: `exp_time`.
+    LoginSucceeded = 5,
+    /// A login started in the background failed or timed out.  Detail: `reason`.
+    LoginFailed = 6,
+}
+
+/// Receives session events along with a JSON object of details.  Called from the
+/// thread that refreshes tokens, never with the session locked.
+pub type EventHandler = Arc<dyn Fn(ZeroIDCEvent, &Value) + Send + Sync>;
+
+/// Where a session's events go.  Delivery holds the read lock, so replacing the
+/// handler waits for any event that's being delivered to the old one.
+#[derive(Clone, Default)]
+pub(crate) struct EventSink(Arc<RwLock<Option<EventHandler>>>);
+
+impl EventSink {
+    pub(crate) fn set(&self, handler: Option<EventHandler>) {
+        *self.0.write().unwrap() = handler;
+    }
+
+    pub(crate) fn deliver(&self, events: Vec<(ZeroIDCEvent, Value)>) {
+        if events.is_empty() {
+            return;
+        }
+        if let Some(handler) = self.0.read().unwrap().as_ref() {
+            for (event, detail) in events {
+                handler(event, &detail);
+            }
+        }
+    }
+}
@@ -10,10 +10,25 @@
  * of this software will be governed by version 2.0 of the Apache License.
  */
 
+// Every export here takes pointers from the host, which has to pass ones it got from
+// us or valid C strings; that's the contract of the C API in zeroidc.h, not something
+// each function can check beyond null.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, error, warn};
 use url::Url;
+use zeroize::Zeroize;
 
+use crate::client_auth::ClientKey;
+use crate::error::{SSOExchangeError, SessionError, ZeroIDCError};
+use crate::events::ZeroIDCEvent;
+use crate::http::HttpConfig;
+use crate::policy::AdmissionPolicy;
+use crate::timing::TimingConfig;
 use crate::ZeroIDC;
 
 #[cfg(any(
@@ -30,24 +45,62 @@ pub extern "C" fn zeroidc_new(
     auth_endpoint: *const c_char,
     provider: *const c_char,
     web_listen_port: u16,
+    network_id: *const c_char,
+    home_path: *const c_char,
 ) -> *mut ZeroIDC {
+    zeroidc_new_with_http_config(
+        issuer,
+        client_id,
+        auth_endpoint,
+        provider,
+        web_listen_port,
+        network_id,
+        home_path,
+        std::ptr::null(),
+    )
+}
+
+/// Like `zeroidc_new`, with the HTTP client configured by `http_config_json` (see
+/// [`crate::http`]).  A null config uses the defaults.  An invalid one fails rather
+/// than silently ignoring the proxy or CAs it names.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn zeroidc_new_with_http_config(
+    issuer: *const c_char,
+    client_id: *const c_char,
+    auth_endpoint: *const c_char,
+    provider: *const c_char,
+    web_listen_port: u16,
+    network_id: *const c_char,
+    home_path: *const c_char,
+    http_config_json: *const c_char,
+) -> *mut ZeroIDC {
+    ztlog::init();
+
     if issuer.is_null() {
-        println!("issuer is null");
+        error!("issuer is null");
         return std::ptr::null_mut();
     }
 
     if client_id.is_null() {
-        println!("client_id is null");
+        error!("client_id is null");
         return std::ptr::null_mut();
     }
 
     if provider.is_null() {
-        println!("provider is null");
+        error!("provider is null");
         return std::ptr::null_mut();
     }
 
     if auth_endpoint.is_null() {
-        println!("auth_endpoint is null");
+        error!("auth_endpoint is null");
         return std::ptr::null_mut();
     }
 
@@ -55,16 +108,40 @@ pub extern "C" fn zeroidc_new(
     let client_id = unsafe { CStr::from_ptr(client_id) };
     let provider = unsafe { CStr::from_ptr(provider) };
     let auth_endpoint = unsafe { CStr::from_ptr(auth_endpoint) };
-    match ZeroIDC::new(
+    let http_config = if http_config_json.is_null() {
+        HttpConfig::default()
+    } else {
+        let json = unsafe { CStr::from_ptr(http_config_json) }.to_str().unwrap();
+        match HttpConfig::from_json(json) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("error parsing HTTP config: {}", e);
+                return std::ptr::null_mut();
+            }
+        }
+    };
+    match ZeroIDC::with_http_config(
         issuer.to_str().unwrap(),
         client_id.to_str().unwrap(),
         provider.to_str().unwrap(),
         auth_endpoint.to_str().unwrap(),
         web_listen_port,
+        &http_config,
     ) {
-        Ok(idc) => Box::into_raw(Box::new(idc)),
+        Ok(mut idc) => {
+            if !network_id.is_null() {
+                idc.set_network_id(unsafe { CStr::from_ptr(network_id) }.to_str().unwrap());
+            }
+            // session persistence is optional. a null home path or network id turns it off
+            if !network_id.is_null() && !home_path.is_null() {
+                let network_id = unsafe { CStr::from_ptr(network_id) }.to_str().unwrap();
+                let home_path = unsafe { CStr::from_ptr(home_path) }.to_str().unwrap();
+                idc.enable_session_persistence(Path::new(home_path), network_id);
+            }
+            Box::into_raw(Box::new(idc))
+        }
         Err(s) => {
-            println!("Error creating ZeroIDC instance: {}", s);
+            error!("Error creating ZeroIDC instance: {}", s);
             std::ptr::null_mut()
         }
     }
@@ -86,6 +163,8 @@ pub extern "C" fn zeroidc_delete(ptr: *mut ZeroIDC) {
         assert!(!ptr.is_null());
         &mut *ptr
     };
+    // make sure nothing calls back into the host once it's let go of us
+    idc.set_event_handler(None);
     idc.stop();
 
     unsafe {
@@ -152,6 +231,85 @@ pub extern "C" fn zeroidc_get_exp_time(ptr: *mut ZeroIDC) -> u64 {
     id.get_exp_time()
 }
 
+/// Returns a JSON snapshot of the session: state, last error, last refresh time, expiry,
+/// issuer and subject.  Free with `free_cstr`.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_get_status_json(idc: *mut ZeroIDC) -> *mut c_char {
+    if idc.is_null() {
+        error!("idc is null");
+        return std::ptr::null_mut();
+    }
+    let idc = unsafe { &mut *idc };
+
+    match serde_json::to_string(&idc.status()) {
+        Ok(json) => to_c_string(json),
+        Err(e) => {
+            error!("error serializing status: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Returns the logged in user's claims as JSON: subject, issuer, email,
+/// email_verified, name, preferred_username and the provider profile's extra claims.
+/// Null if nobody is logged in.  Free with `free_cstr`.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_get_claims_json(idc: *mut ZeroIDC) -> *mut c_char {
+    if idc.is_null() {
+        error!("idc is null");
+        return std::ptr::null_mut();
+    }
+    let idc = unsafe { &mut *idc };
+
+    let claims = match idc.claims() {
+        Some(c) => c,
+        None => return std::ptr::null_mut(),
+    };
+    match serde_json::to_string(&claims) {
+        Ok(json) => to_c_string(json),
+        Err(e) => {
+            error!("error serializing claims: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Returns why the session last failed, or null if it hasn't.  Free with `free_cstr`.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_get_last_error(idc: *mut ZeroIDC) -> *mut c_char {
+    if idc.is_null() {
+        error!("idc is null");
+        return std::ptr::null_mut();
+    }
+    let idc = unsafe { &mut *idc };
+
+    match idc.last_error() {
+        Some(e) => to_c_string(e),
+        None => std::ptr::null_mut(),
+    }
+}
+
 #[cfg(any(
     all(target_os = "linux", target_arch = "x86"),
     all(target_os = "linux", target_arch = "x86_64"),
@@ -167,12 +325,12 @@ pub extern "C" fn zeroidc_set_nonce_and_csrf(ptr: *mut ZeroIDC, csrf_token: *con
     };
 
     if csrf_token.is_null() {
-        println!("csrf_token is null");
+        error!("csrf_token is null");
         return;
     }
 
     if nonce.is_null() {
-        println!("nonce is null");
+        error!("nonce is null");
         return;
     }
 
@@ -182,6 +340,7 @@ pub extern "C" fn zeroidc_set_nonce_and_csrf(ptr: *mut ZeroIDC, csrf_token: *con
     idc.set_nonce_and_csrf(csrf_token, nonce);
 }
 
+/// Frees a string returned by zeroidc.  Its contents are zeroed first.
 #[cfg(any(
     all(target_os = "linux", target_arch = "x86"),
     all(target_os = "linux", target_arch = "x86_64"),
@@ -192,15 +351,16 @@ pub extern "C" fn zeroidc_set_nonce_and_csrf(ptr: *mut ZeroIDC, csrf_token: *con
 #[no_mangle]
 pub extern "C" fn free_cstr(s: *mut c_char) {
     if s.is_null() {
-        println!("passed a null object");
+        error!("passed a null object");
         return;
     }
 
-    unsafe {
-        let _ = CString::from_raw(s);
-    }
+    scrub(unsafe { CString::from_raw(s) });
 }
 
+/// Returns the URL to log in at, to be freed with `free_cstr`.  With pushed
+/// authorization requests this may wait for the IdP, and the URL is only good for as
+/// long as the IdP says, so ask again rather than keeping it.
 #[cfg(any(
     all(target_os = "linux", target_arch = "x86"),
     all(target_os = "linux", target_arch = "x86_64"),
@@ -211,7 +371,7 @@ pub extern "C" fn free_cstr(s: *mut c_char) {
 #[no_mangle]
 pub extern "C" fn zeroidc_get_auth_url(ptr: *mut ZeroIDC) -> *mut c_char {
     if ptr.is_null() {
-        println!("passed a null object");
+        error!("passed a null object");
         return std::ptr::null_mut();
     }
     let idc = unsafe { &mut *ptr };
@@ -220,6 +380,120 @@ pub extern "C" fn zeroidc_get_auth_url(ptr: *mut ZeroIDC) -> *mut c_char {
     s.into_raw()
 }
 
+/// Stable error codes reported across the FFI.  Values are never reused or renumbered.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZeroIDCErrorCode {
+    Ok = 0,
+    /// A null or non-UTF-8 argument was passed in.
+    InvalidArgument = 1,
+    /// No login is in progress, or the state token doesn't match it.
+    InvalidState = 2,
+    /// Discovering the IdP's configuration failed.
+    Discovery = 3,
+    /// A URL from the IdP or central couldn't be parsed.
+    UrlParse = 4,
+    /// The IdP answered with an OAuth error such as `invalid_grant`.
+    ProviderError = 5,
+    /// The IdP couldn't be reached or sent something that isn't an OAuth response.
+    ProviderRequest = 6,
+    /// The IdP's response had no ID token, or the ID token failed verification.
+    TokenVerification = 7,
+    /// Central needs more license seats for the network (HTTP 402).
+    LicenseRequired = 8,
+    /// Central rejected the ID token.
+    Central = 9,
+    /// Central couldn't be reached.
+    CentralRequest = 10,
+    /// The loopback redirect listener couldn't be started.
+    Listener = 11,
+    /// The client's secret or private key couldn't be used.
+    ClientAuth = 12,
+    /// The HTTP client couldn't be set up, e.g. an invalid proxy URL or CA file.
+    HttpConfig = 13,
+    /// The ID token doesn't meet the local admission policy.
+    PolicyRejected = 14,
+    /// Central accepted the ID token but sent a response that doesn't make sense, e.g.
+    /// for another network.
+    InvalidCentralResponse = 15,
+}
+
+impl From<&ZeroIDCError> for ZeroIDCErrorCode {
+    fn from(e: &ZeroIDCError) -> Self {
+        match e {
+            ZeroIDCError::DiscoveryError(_) => ZeroIDCErrorCode::Discovery,
+            ZeroIDCError::ParseError(_) => ZeroIDCErrorCode::UrlParse,
+            ZeroIDCError::Listener(_) => ZeroIDCErrorCode::Listener,
+            ZeroIDCError::HttpConfig(_) => ZeroIDCErrorCode::HttpConfig,
+        }
+    }
+}
+
+impl From<&SSOExchangeError> for ZeroIDCErrorCode {
+    fn from(e: &SSOExchangeError) -> Self {
+        match e {
+            SSOExchangeError::InvalidState(_) => ZeroIDCErrorCode::InvalidState,
+            SSOExchangeError::Setup(e) => e.into(),
+            SSOExchangeError::Provider { .. } => ZeroIDCErrorCode::ProviderError,
+            SSOExchangeError::ProviderRequest(_) => ZeroIDCErrorCode::ProviderRequest,
+            SSOExchangeError::Session(SessionError::PolicyRejected(_)) => ZeroIDCErrorCode::PolicyRejected,
+            SSOExchangeError::Session(_) => ZeroIDCErrorCode::TokenVerification,
+            SSOExchangeError::ClientAuth(_) => ZeroIDCErrorCode::ClientAuth,
+            SSOExchangeError::LicenseRequired => ZeroIDCErrorCode::LicenseRequired,
+            SSOExchangeError::Central { .. } => ZeroIDCErrorCode::Central,
+            SSOExchangeError::CentralRequest(_) => ZeroIDCErrorCode::CentralRequest,
+            SSOExchangeError::InvalidCentralResponse(_) => ZeroIDCErrorCode::InvalidCentralResponse,
+        }
+    }
+}
+
+/// Result of `zeroidc_token_exchange`.  Free with `zeroidc_exchange_result_free`.
+#[repr(C)]
+pub struct ZeroIDCExchangeResult {
+    pub code: ZeroIDCErrorCode,
+    /// HTTP status of the failed request to central, or 0.
+    pub http_status: u16,
+    /// What went wrong, for showing to the user.  Null on success.
+    pub message: *mut c_char,
+    /// On success, when the ID token expires, in seconds since the epoch.
+    pub exp_time: u64,
+    /// On success, the network central authorized, or 0 if it didn't say.
+    pub network_id: u64,
+    /// On success, the member central authorized, or 0 if it didn't say.
+    pub member_id: u64,
+    /// On success, when central's authorization runs out, in seconds since the epoch,
+    /// or 0 if it didn't say.
+    pub central_exp_time: u64,
+}
+
+/// Zeroes a string handed back by the host before freeing it, since it may hold a
+/// token.
+fn scrub(s: CString) {
+    s.into_bytes_with_nul().zeroize();
+}
+
+/// Copies `s` into a C string, dropping any NUL bytes rather than failing.
+fn to_c_string(s: String) -> *mut c_char {
+    let s = CString::new(s).unwrap_or_else(|e| {
+        let mut bytes = e.into_vec();
+        bytes.retain(|b| *b != 0);
+        CString::new(bytes).unwrap_or_default()
+    });
+    s.into_raw()
+}
+
+fn exchange_error(code: ZeroIDCErrorCode, http_status: u16, message: String) -> *mut ZeroIDCExchangeResult {
+    Box::into_raw(Box::new(ZeroIDCExchangeResult {
+        code,
+        http_status,
+        message: to_c_string(message),
+        exp_time: 0,
+        network_id: 0,
+        member_id: 0,
+        central_exp_time: 0,
+    }))
+}
+
 #[cfg(any(
     all(target_os = "linux", target_arch = "x86"),
     all(target_os = "linux", target_arch = "x86_64"),
@@ -228,50 +502,89 @@ pub extern "C" fn zeroidc_get_auth_url(ptr: *mut ZeroIDC) -> *mut c_char {
     target_os = "macos",
 ))]
 #[no_mangle]
-pub extern "C" fn zeroidc_token_exchange(idc: *mut ZeroIDC, code: *const c_char) -> *mut c_char {
+pub extern "C" fn zeroidc_token_exchange(idc: *mut ZeroIDC, code: *const c_char) -> *mut ZeroIDCExchangeResult {
     if idc.is_null() {
-        println!("idc is null");
-        return std::ptr::null_mut();
+        error!("idc is null");
+        return exchange_error(ZeroIDCErrorCode::InvalidArgument, 0, "idc is null".to_string());
     }
 
     if code.is_null() {
-        println!("code is null");
-        return std::ptr::null_mut();
+        error!("code is null");
+        return exchange_error(ZeroIDCErrorCode::InvalidArgument, 0, "code is null".to_string());
     }
     let idc = unsafe { &mut *idc };
 
-    let code = unsafe { CStr::from_ptr(code) }.to_str().unwrap();
+    let code = match unsafe { CStr::from_ptr(code) }.to_str() {
+        Ok(code) => code,
+        Err(_) => {
+            return exchange_error(
+                ZeroIDCErrorCode::InvalidArgument,
+                0,
+                "code is not valid UTF-8".to_string(),
+            );
+        }
+    };
 
     let ret = idc.do_token_exchange(code);
     match ret {
         Ok(ret) => {
             #[cfg(debug_assertions)]
             {
-                println!("do_token_exchange ret: {}", ret);
+                debug!("do_token_exchange ret: {:?}", ret);
             }
-            let ret = CString::new(ret).unwrap();
-            ret.into_raw()
+            Box::into_raw(Box::new(ZeroIDCExchangeResult {
+                code: ZeroIDCErrorCode::Ok,
+                http_status: 0,
+                message: std::ptr::null_mut(),
+                exp_time: idc.get_exp_time(),
+                network_id: ret.network_id_u64(),
+                member_id: ret.member_id_u64(),
+                central_exp_time: ret.exp_time().unwrap_or(0),
+            }))
         }
         Err(e) => {
             #[cfg(debug_assertions)]
             {
-                println!("do_token_exchange err: {}", e);
+                warn!("do_token_exchange err: {}", e);
             }
-            let errstr = format!("{{\"errorMessage\": \"{}\"}}", e);
-            let ret = CString::new(errstr).unwrap();
-            ret.into_raw()
+            let http_status = match e {
+                SSOExchangeError::LicenseRequired => 402,
+                SSOExchangeError::Central { status, .. } => status,
+                _ => 0,
+            };
+            exchange_error((&e).into(), http_status, e.to_string())
         }
     }
 }
 
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_exchange_result_free(res: *mut ZeroIDCExchangeResult) {
+    if res.is_null() {
+        error!("passed a null object");
+        return;
+    }
+
+    let res = unsafe { Box::from_raw(res) };
+    if !res.message.is_null() {
+        scrub(unsafe { CString::from_raw(res.message) });
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn zeroidc_get_url_param_value(param: *const c_char, path: *const c_char) -> *mut c_char {
     if param.is_null() {
-        println!("param is null");
+        error!("param is null");
         return std::ptr::null_mut();
     }
     if path.is_null() {
-        println!("path is null");
+        error!("path is null");
         return std::ptr::null_mut();
     }
     let param = unsafe { CStr::from_ptr(param) }.to_str().unwrap();
@@ -294,7 +607,7 @@ pub extern "C" fn zeroidc_get_url_param_value(param: *const c_char, path: *const
 #[no_mangle]
 pub extern "C" fn zeroidc_network_id_from_state(state: *const c_char) -> *mut c_char {
     if state.is_null() {
-        println!("state is null");
+        error!("state is null");
         return std::ptr::null_mut();
     }
 
@@ -320,10 +633,421 @@ pub extern "C" fn zeroidc_network_id_from_state(state: *const c_char) -> *mut c_
 #[no_mangle]
 pub extern "C" fn zeroidc_kick_refresh_thread(idc: *mut ZeroIDC) {
     if idc.is_null() {
-        println!("idc is null");
+        error!("idc is null");
         return;
     }
     let idc = unsafe { &mut *idc };
 
     idc.kick_refresh_thread();
 }
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_start_device_flow(idc: *mut ZeroIDC) -> *mut c_char {
+    if idc.is_null() {
+        error!("idc is null");
+        return std::ptr::null_mut();
+    }
+    let idc = unsafe { &mut *idc };
+
+    match idc.start_device_flow() {
+        Ok(auth) => match serde_json::to_string(&auth) {
+            Ok(s) => to_c_string(s),
+            Err(_) => std::ptr::null_mut(),
+        },
+        Err(e) => {
+            warn!("error starting device flow: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Starts a login through zeroidc's own loopback redirect listener instead of the
+/// service's `/sso` endpoint.  Returns the URL to open in the user's browser, or null
+/// on error.  Free with `free_cstr`.  The outcome is reported to the event callback.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_start_loopback_login(idc: *mut ZeroIDC) -> *mut c_char {
+    if idc.is_null() {
+        error!("idc is null");
+        return std::ptr::null_mut();
+    }
+    let idc = unsafe { &mut *idc };
+
+    match idc.start_loopback_login() {
+        Ok(url) => to_c_string(url),
+        Err(e) => {
+            warn!("error starting loopback login: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_load_provider_profiles(idc: *mut ZeroIDC, profiles_json: *const c_char) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+
+    if profiles_json.is_null() {
+        error!("profiles_json is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+    let profiles_json = unsafe { CStr::from_ptr(profiles_json) }.to_str().unwrap();
+
+    match idc.load_provider_profiles(profiles_json) {
+        Ok(found) => found,
+        Err(e) => {
+            warn!("error parsing provider profiles: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_timing_config(idc: *mut ZeroIDC, timing_json: *const c_char) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+
+    if timing_json.is_null() {
+        error!("timing_json is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+    let timing_json = unsafe { CStr::from_ptr(timing_json) }.to_str().unwrap();
+
+    match TimingConfig::from_json(timing_json) {
+        Ok(timing) => {
+            idc.set_timing(timing);
+            true
+        }
+        Err(e) => {
+            warn!("error parsing timing config: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_admission_policy(idc: *mut ZeroIDC, policy_json: *const c_char) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+
+    if policy_json.is_null() {
+        error!("policy_json is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+    let policy_json = unsafe { CStr::from_ptr(policy_json) }.to_str().unwrap();
+
+    match AdmissionPolicy::from_json(policy_json) {
+        Ok(policy) => {
+            idc.set_admission_policy(policy);
+            true
+        }
+        Err(e) => {
+            warn!("error parsing admission policy: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_client_secret(idc: *mut ZeroIDC, secret: *const c_char) {
+    if idc.is_null() {
+        error!("idc is null");
+        return;
+    }
+    let idc = unsafe { &mut *idc };
+
+    // a null secret makes this a public client again
+    let secret = if secret.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(secret) }.to_str().unwrap())
+    };
+
+    idc.set_client_secret(secret);
+}
+
+/// Sets the private key for `private_key_jwt` from a PEM string.  `kid` may be null.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_client_key_pem(idc: *mut ZeroIDC, pem: *const c_char, kid: *const c_char) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+
+    if pem.is_null() {
+        error!("pem is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+    let pem = unsafe { CStr::from_ptr(pem) }.to_str().unwrap();
+    let kid = if kid.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(kid) }.to_str().unwrap())
+    };
+
+    match ClientKey::from_pem(pem, kid) {
+        Ok(key) => {
+            idc.set_client_key(Some(key));
+            true
+        }
+        Err(e) => {
+            warn!("{}", e);
+            false
+        }
+    }
+}
+
+/// Sets the private key for `private_key_jwt` from a PEM file.  `kid` may be null.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_client_key_file(idc: *mut ZeroIDC, path: *const c_char, kid: *const c_char) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+
+    if path.is_null() {
+        error!("path is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+    let path = unsafe { CStr::from_ptr(path) }.to_str().unwrap();
+    let kid = if kid.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(kid) }.to_str().unwrap())
+    };
+
+    match ClientKey::from_file(Path::new(path), kid) {
+        Ok(key) => {
+            idc.set_client_key(Some(key));
+            true
+        }
+        Err(e) => {
+            warn!("{}", e);
+            false
+        }
+    }
+}
+
+/// Turns DPoP on or off for new sessions.  Returns false if it was turned on but the
+/// IdP doesn't support it.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_dpop(idc: *mut ZeroIDC, enabled: bool) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+
+    idc.set_dpop(enabled)
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_auth_param(idc: *mut ZeroIDC, name: *const c_char, value: *const c_char) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+
+    if name.is_null() {
+        error!("name is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+    let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap();
+
+    // a null value removes the parameter
+    let value = if value.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(value) }.to_str().unwrap())
+    };
+
+    idc.set_auth_param(name, value)
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_logout(idc: *mut ZeroIDC) -> bool {
+    if idc.is_null() {
+        error!("idc is null");
+        return false;
+    }
+    let idc = unsafe { &mut *idc };
+
+    idc.logout()
+}
+
+/// Called with the context pointer given to `zeroidc_set_event_callback`, the event,
+/// and a JSON object with details.  `detail` is only valid during the call.  Runs on
+/// one of zeroidc's threads, possibly for several sessions at once, and must not call
+/// `zeroidc_set_event_callback`.
+pub type ZeroIDCEventCallback = Option<extern "C" fn(ctx: *mut c_void, event: ZeroIDCEvent, detail: *const c_char)>;
+
+/// The host's context pointer.  The host promises it stays valid, from any thread,
+/// until the callback is replaced or removed.
+struct CallbackContext(*mut c_void);
+unsafe impl Send for CallbackContext {}
+unsafe impl Sync for CallbackContext {}
+
+impl CallbackContext {
+    fn ptr(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// Registers `callback` to be told about session events, or removes the current one if
+/// `callback` is null.  Once this returns the previous callback won't be called again.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_event_callback(idc: *mut ZeroIDC, callback: ZeroIDCEventCallback, ctx: *mut c_void) {
+    if idc.is_null() {
+        error!("idc is null");
+        return;
+    }
+    let idc = unsafe { &mut *idc };
+
+    let callback = match callback {
+        Some(callback) => callback,
+        None => {
+            idc.set_event_handler(None);
+            return;
+        }
+    };
+
+    let ctx = CallbackContext(ctx);
+    idc.set_event_handler(Some(Arc::new(move |event, detail| {
+        let detail = CString::new(detail.to_string()).unwrap_or_default();
+        callback(ctx.ptr(), event, detail.as_ptr());
+    })));
+}
+
+/// Called with the context pointer given to `zeroidc_set_log_callback`, the level (1 for
+/// error through 5 for trace), the module the record comes from, and the message with
+/// secrets already redacted.  The strings are only valid during the call.  May be
+/// called from any thread.
+pub type ZeroIDCLogCallback =
+    Option<extern "C" fn(ctx: *mut c_void, level: c_int, target: *const c_char, message: *const c_char)>;
+
+/// Routes zeroidc's log records up to `max_level` to `callback`, or drops them if
+/// `callback` is null.  A `max_level` of 0 turns logging off.  Applies
+/// to every instance.  Once this returns the previous callback won't be called again.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+#[no_mangle]
+pub extern "C" fn zeroidc_set_log_callback(callback: ZeroIDCLogCallback, ctx: *mut c_void, max_level: c_int) {
+    let max_level = ztlog::level_filter_from_int(max_level);
+    let callback = match callback {
+        Some(callback) => callback,
+        None => {
+            ztlog::set_sink(None, max_level);
+            return;
+        }
+    };
+
+    let ctx = CallbackContext(ctx);
+    ztlog::set_sink(
+        Some(Arc::new(move |level, target, message| {
+            let target = CString::new(target).unwrap_or_default();
+            let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+            callback(ctx.ptr(), ztlog::level_to_int(level), target.as_ptr(), message.as_ptr());
+        })),
+        max_level,
+    );
+}
@@ -0,0 +1,201 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! The HTTP client all IdP and central requests go through.
+//!
+//! Networks behind an intercepting proxy can set the proxy explicitly and trust the
+//! proxy's CA, e.g.
+//!
+//! ```json
+//! {
+//!     "proxy": "http://proxy.corp.example:3128",
+//!     "ca_files": ["/etc/ssl/corp-root.pem"],
+//!     "connect_timeout": 10,
+//!     "timeout": 30,
+//!     "user_agent": "ZeroTier-SSO"
+//! }
+//! ```
+//!
+//! Timeouts are in seconds.  Without a `proxy` the usual `HTTPS_PROXY`-style variables
+//! are honored.  Like `openidconnect::reqwest::http_client`, requests to the IdP don't
+//! follow redirects.  Posts to central do, as they did before they went through here.
+//!
+//! An IdP or central that wants TLS client authentication gets the certificate chain in
+//! `client_cert` and its PKCS#8 key (`BEGIN PRIVATE KEY`) in `client_key`, both PEM.
+//! That's the one format both TLS backends, picked with the `native-tls` and `rustls`
+//! features, read alike.  Both trust the system's roots plus `ca_files`.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use openidconnect::http::header::DATE;
+use openidconnect::reqwest::Error;
+use openidconnect::{HttpRequest, HttpResponse};
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, Identity, Proxy};
+use serde::Deserialize;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use crate::error::HttpConfigError;
+use crate::timing::{offset_from_date, LARGE_CLOCK_OFFSET};
+
+const DEFAULT_USER_AGENT: &str = concat!("zeroidc/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub proxy: Option<String>,
+    /// PEM files with CA certificates to trust in addition to the system's.
+    pub ca_files: Vec<PathBuf>,
+    pub connect_timeout: Option<u64>,
+    /// Limit on a whole request, from connecting to reading the body.
+    pub timeout: Option<u64>,
+    pub user_agent: Option<String>,
+    /// PEM certificate chain for TLS client authentication.
+    pub client_cert: Option<PathBuf>,
+    /// PEM PKCS#8 key for `client_cert`.
+    pub client_key: Option<PathBuf>,
+}
+
+impl HttpConfig {
+    pub fn from_json(json: &str) -> Result<HttpConfig, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// A configured client.  Clones share connections.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    /// The same, but following redirects.
+    central: Client,
+    /// How far the IdP's clock is ahead of ours, as of its last response.
+    clock_offset: Arc<Mutex<Option<i64>>>,
+}
+
+impl HttpClient {
+    pub fn new(config: &HttpConfig) -> Result<HttpClient, HttpConfigError> {
+        Ok(HttpClient {
+            client: builder(config)?.redirect(Policy::none()).build()?,
+            central: builder(config)?.build()?,
+            clock_offset: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// The underlying client, which doesn't follow redirects.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The client for posts to central, which follows redirects.
+    pub fn central(&self) -> &Client {
+        &self.central
+    }
+
+    /// Seconds the IdP's clock is ahead of ours, if it has sent a `Date` header yet.
+    pub fn clock_offset(&self) -> Option<i64> {
+        *self.clock_offset.lock().unwrap()
+    }
+
+    fn update_clock_offset(&self, res: &HttpResponse) {
+        let offset = match res
+            .headers
+            .get(DATE)
+            .and_then(|d| offset_from_date(d, SystemTime::now()))
+        {
+            Some(o) => o,
+            None => return,
+        };
+        let previous = self.clock_offset.lock().unwrap().replace(offset);
+        let large = |o: i64| o.abs() >= LARGE_CLOCK_OFFSET;
+        if large(offset) && !previous.map(large).unwrap_or(false) {
+            warn!(
+                offset,
+                "local clock is {}s off from the identity provider's",
+                offset.abs()
+            );
+        }
+    }
+
+    /// Sends an openidconnect request.  A drop-in for
+    /// `openidconnect::reqwest::http_client`.
+    pub fn request(&self, req: HttpRequest) -> Result<HttpResponse, Error<reqwest::Error>> {
+        let mut builder = self.client.request(req.method, req.url.as_str()).body(req.body);
+        for (name, value) in req.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        let mut res = builder.send().map_err(Error::Reqwest)?;
+
+        let mut body = Vec::new();
+        res.read_to_end(&mut body).map_err(Error::Io)?;
+        let res = HttpResponse {
+            status_code: res.status(),
+            headers: res.headers().to_owned(),
+            body,
+        };
+        self.update_clock_offset(&res);
+        Ok(res)
+    }
+}
+
+/// A client builder with everything in `config` applied.
+fn builder(config: &HttpConfig) -> Result<ClientBuilder, HttpConfigError> {
+    let mut builder = Client::builder().user_agent(config.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT));
+
+    if let Some(proxy) = config.proxy.as_ref() {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    for path in config.ca_files.iter() {
+        let pem = fs::read(path).map_err(|source| HttpConfigError::CaFile { path: path.clone(), source })?;
+        for cert in Certificate::from_pem_bundle(&pem)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    match (config.client_cert.as_ref(), config.client_key.as_ref()) {
+        (Some(cert), Some(key)) => builder = builder.identity(read_identity(cert, key)?),
+        (None, None) => {}
+        _ => return Err(HttpConfigError::IncompleteClientCert),
+    }
+    if let Some(secs) = config.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = config.timeout {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    Ok(builder)
+}
+
+fn read_identity(cert: &Path, key: &Path) -> Result<Identity, HttpConfigError> {
+    let read = |path: &Path| {
+        fs::read(path)
+            .map(Zeroizing::new)
+            .map_err(|source| HttpConfigError::ClientCertFile { path: path.to_path_buf(), source })
+    };
+    let (cert, key) = (read(cert)?, read(key)?);
+
+    #[cfg(feature = "native-tls")]
+    let identity = Identity::from_pkcs8_pem(&cert, &key)?;
+    #[cfg(all(feature = "rustls", not(feature = "native-tls")))]
+    let identity = {
+        let mut pem = Zeroizing::new(Vec::with_capacity(key.len() + cert.len() + 1));
+        pem.extend_from_slice(&key);
+        pem.push(b'\n');
+        pem.extend_from_slice(&cert);
+        Identity::from_pem(&pem)?
+    };
+    Ok(identity)
+}
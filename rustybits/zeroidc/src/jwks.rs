@@ -0,0 +1,230 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Cache for the IdP's signing keys.
+//!
+//! Discovery only fetches the JWKS once, so a node that stays up across a key
+//! rotation would start rejecting perfectly good ID tokens.  The cache refetches the
+//! key set when the HTTP cache lifetime runs out or when a token shows up signed with
+//! a key we've never seen, and keeps the last good set if the IdP can't be reached.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::Utc;
+use openidconnect::core::{CoreIdTokenVerifier, CoreJsonWebKeySet, CoreJwsSigningAlgorithm};
+use openidconnect::http::header::{HeaderMap, ACCEPT, AGE, CACHE_CONTROL, EXPIRES};
+use openidconnect::http::{HeaderValue, Method, StatusCode};
+use openidconnect::{ClientId, HttpRequest, IssuerUrl, JsonWebKeySetUrl};
+use tracing::{debug, warn};
+
+use crate::http::HttpClient;
+
+/// Lifetime used when the JWKS response has no usable cache headers.
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+/// Upper bound on how long we trust a key set, whatever the headers say.
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Default minimum time between fetches, so tokens with bogus key IDs can't make us
+/// hammer the IdP.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct JwksCache {
+    http: HttpClient,
+    url: JsonWebKeySetUrl,
+    client_id: ClientId,
+    issuer: IssuerUrl,
+    signing_algs: Option<Vec<CoreJwsSigningAlgorithm>>,
+    /// How far past `exp` tokens are accepted.
+    clock_skew: Duration,
+    min_refetch_interval: Duration,
+
+    keys: CoreJsonWebKeySet,
+    expires: Instant,
+    last_fetch: Option<Instant>,
+}
+
+impl JwksCache {
+    /// Creates a cache seeded with the key set fetched during discovery.
+    pub fn new(
+        http: HttpClient,
+        url: JsonWebKeySetUrl,
+        client_id: ClientId,
+        issuer: IssuerUrl,
+        signing_algs: Option<Vec<CoreJwsSigningAlgorithm>>,
+        keys: CoreJsonWebKeySet,
+    ) -> JwksCache {
+        let now = Instant::now();
+        JwksCache {
+            http,
+            url,
+            client_id,
+            issuer,
+            signing_algs,
+            clock_skew: Duration::ZERO,
+            min_refetch_interval: MIN_REFETCH_INTERVAL,
+            keys,
+            expires: now + DEFAULT_TTL,
+            last_fetch: Some(now),
+        }
+    }
+
+    pub fn set_clock_skew(&mut self, skew: Duration) {
+        self.clock_skew = skew;
+    }
+
+    pub fn set_min_refetch_interval(&mut self, interval: Duration) {
+        self.min_refetch_interval = interval;
+    }
+
+    pub fn keys(&self) -> &CoreJsonWebKeySet {
+        &self.keys
+    }
+
+    /// When the cached key set goes stale.
+    pub fn expires(&self) -> Instant {
+        self.expires
+    }
+
+    /// Takes what `other`, a copy of this cache used while the session wasn't locked,
+    /// fetched since, if anything.
+    pub fn update_from(&mut self, other: JwksCache) {
+        if other.last_fetch > self.last_fetch {
+            self.keys = other.keys;
+            self.expires = other.expires;
+            self.last_fetch = other.last_fetch;
+        }
+    }
+
+    /// Builds an ID token verifier for the client using the currently cached keys.
+    pub fn verifier(&self) -> CoreIdTokenVerifier<'static> {
+        let skew = chrono::Duration::from_std(self.clock_skew).unwrap_or_else(|_| chrono::Duration::zero());
+        let verifier =
+            CoreIdTokenVerifier::new_public_client(self.client_id.clone(), self.issuer.clone(), self.keys.clone())
+                .set_time_fn(move || Utc::now() - skew);
+        match self.signing_algs.clone() {
+            Some(algs) => verifier.set_allowed_algs(algs),
+            None => verifier,
+        }
+    }
+
+    /// Refetches the key set if its cache lifetime has run out.
+    pub fn refresh_if_stale(&mut self) {
+        if Instant::now() >= self.expires && self.may_fetch() {
+            self.fetch();
+        }
+    }
+
+    /// Called when a token names a key we don't have.  Refetches the key set unless we
+    /// did so very recently, and returns true if a new set was loaded.
+    pub fn refresh_for_unknown_key(&mut self) -> bool {
+        if !self.may_fetch() {
+            debug!("unknown signing key, but JWKS was fetched recently. not refetching");
+            return false;
+        }
+        self.fetch()
+    }
+
+    fn may_fetch(&self) -> bool {
+        match self.last_fetch {
+            Some(t) => t.elapsed() >= self.min_refetch_interval,
+            None => true,
+        }
+    }
+
+    fn fetch(&mut self) -> bool {
+        let now = Instant::now();
+        self.last_fetch = Some(now);
+
+        let req = HttpRequest {
+            url: self.url.url().clone(),
+            method: Method::GET,
+            headers: vec![(ACCEPT, HeaderValue::from_static("application/json"))]
+                .into_iter()
+                .collect(),
+            body: Vec::new(),
+        };
+
+        let res = match self.http.request(req) {
+            Ok(res) => res,
+            Err(e) => {
+                warn!(
+                    "error fetching JWKS from {}: {}. keeping cached keys",
+                    self.url.as_str(),
+                    e
+                );
+                return false;
+            }
+        };
+
+        if res.status_code != StatusCode::OK {
+            warn!(
+                "error fetching JWKS from {}: HTTP {}. keeping cached keys",
+                self.url.as_str(),
+                res.status_code
+            );
+            return false;
+        }
+
+        match serde_json::from_slice::<CoreJsonWebKeySet>(&res.body) {
+            Ok(keys) => {
+                debug!("fetched {} signing keys from {}", keys.keys().len(), self.url.as_str());
+                self.keys = keys;
+                self.expires = now + cache_lifetime(&res.headers);
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "error parsing JWKS from {}: {}. keeping cached keys",
+                    self.url.as_str(),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Works out how long a response may be cached from its `Cache-Control`, `Age` and
+/// `Expires` headers.
+pub(crate) fn cache_lifetime(headers: &HeaderMap) -> Duration {
+    let age = headers
+        .get(AGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    if let Some(cc) = headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) {
+        for directive in cc.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+            if directive == "no-cache" || directive == "no-store" {
+                return Duration::ZERO;
+            }
+            if let Some(max_age) = directive.strip_prefix("max-age=") {
+                if let Ok(max_age) = max_age.trim_matches('"').parse::<u64>() {
+                    return Duration::from_secs(max_age.saturating_sub(age)).min(MAX_TTL);
+                }
+            }
+        }
+    }
+
+    if let Some(expires) = headers
+        .get(EXPIRES)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+    {
+        return expires
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+            .min(MAX_TTL);
+    }
+
+    DEFAULT_TTL
+}
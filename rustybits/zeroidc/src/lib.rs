@@ -10,8 +10,41 @@
  * of this software will be governed by version 2.0 of the Apache License.
  */
 
+pub mod central;
+pub mod claims;
+pub mod client_auth;
+pub mod device;
+pub mod dpop;
 pub mod error;
+pub mod events;
 pub mod ext;
+pub mod http;
+pub mod jwks;
+pub mod loopback;
+pub mod metadata;
+mod par;
+pub mod policy;
+pub mod profile;
+mod retry;
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+mod scheduler;
+pub mod secret;
+pub mod session;
+pub mod state;
+#[cfg(all(test, target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests;
+pub mod timing;
+
+#[cfg(all(feature = "native-tls", feature = "rustls"))]
+compile_error!("the native-tls and rustls features are mutually exclusive");
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("one of the native-tls or rustls features is required");
 
 extern crate base64;
 extern crate bytes;
@@ -19,23 +52,50 @@ extern crate openidconnect;
 extern crate time;
 extern crate url;
 
+use crate::central::CentralAuthResponse;
+use crate::claims::SessionClaims;
+use crate::client_auth::{ClientAuth, ClientKey, JWT_BEARER_ASSERTION};
+use crate::dpop::{DpopKey, DPOP_ALG};
 use crate::error::*;
-
-use bytes::Bytes;
-use jwt::Token;
-use openidconnect::core::{CoreClient, CoreProviderMetadata, CoreResponseType};
-use openidconnect::reqwest::http_client;
+use crate::events::{EventHandler, EventSink, ZeroIDCEvent};
+use crate::http::{HttpClient, HttpConfig};
+use crate::jwks::JwksCache;
+use crate::metadata::ZeroIDCProviderMetadata;
+use crate::par::{PendingPush, PushedRequest};
+use crate::policy::AdmissionPolicy;
+use crate::profile::{parse_profiles, ProviderProfile};
+use crate::retry::{backoff_delay, retry_after, RefreshFailure};
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+use crate::scheduler::{next_session_id, scheduler};
+use crate::secret::Secret;
+use crate::session::{SessionStore, StoredSession};
+use crate::state::{SessionState, SessionStatus};
+use crate::timing::{TimingConfig, MIN_REFRESH_INTERVAL};
+
+use openidconnect::core::{
+    CoreClient, CoreIdToken, CoreIdTokenClaims, CoreResponseType, CoreRevocableToken, CoreTokenResponse,
+};
 use openidconnect::{
-    AccessToken, AccessTokenHash, AuthenticationFlow, AuthorizationCode, ClientId, CsrfToken, IssuerUrl, Nonce,
-    OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenResponse,
+    AccessTokenHash, AuthenticationFlow, AuthorizationCode, ClaimsVerificationError, ClientId, CsrfToken,
+    EndSessionUrl, HttpRequest, HttpResponse, IssuerUrl, LogoutRequest, Nonce, NonceVerifier, OAuth2TokenResponse,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, SignatureVerificationError, TokenResponse,
 };
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::str::from_utf8;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::thread::{sleep, spawn, JoinHandle};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-#[cfg(debug_assertions)]
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use time::{format_description, OffsetDateTime};
+use tracing::{debug, error, info, info_span, warn, Span};
 
 use url::Url;
 
@@ -58,20 +118,65 @@ pub struct ZeroIDC {
     target_os = "macos",
 ))]
 struct Inner {
-    running: bool,
+    state: SessionState,
+    issuer: String,
+    client_id: String,
     auth_endpoint: String,
     provider: String,
-    oidc_thread: Option<JoinHandle<()>>,
+    profile: ProviderProfile,
+    auth_params: BTreeMap<String, String>,
+    session_id: u64,
+    /// Shared by every request to the IdP and central.
+    http: HttpClient,
+    provider_meta: ZeroIDCProviderMetadata,
     oidc_client: Option<openidconnect::core::CoreClient>,
-    access_token: Option<AccessToken>,
-    refresh_token: Option<RefreshToken>,
+    client_auth: ClientAuth,
+    /// Whether new sessions should use DPoP.
+    dpop_enabled: bool,
+    /// The proof key for the current or next session.
+    dpop: Option<Arc<DpopKey>>,
+    /// Whether the current tokens are bound to `dpop`.
+    dpop_bound: bool,
+    access_token: Option<Secret>,
+    refresh_token: Option<Secret>,
+    /// The serialized ID token.
+    id_token: Option<Secret>,
+    end_session_endpoint: Option<EndSessionUrl>,
     exp_time: u64,
-    kick: bool,
+    /// When the current ID token was issued, or 0 if we don't know.
+    issued_time: u64,
+    /// When central's authorization of the member runs out, or 0 if central didn't say.
+    central_exp_time: u64,
+    /// Whether refreshes are scheduled ahead of `central_exp_time`.  Not once a refresh
+    /// didn't move it, since another one won't either.
+    follow_central_exp: bool,
+    timing: TimingConfig,
+    policy: AdmissionPolicy,
+    refresh_nonce: Option<Secret>,
+    refresh_failures: u32,
+    last_refresh: Option<SystemTime>,
+    subject: Option<String>,
+    /// What the verified ID token says about the user.
+    claims: Option<SessionClaims>,
+    device_pending: bool,
+    loopback_pending: bool,
+    /// The redirect URI served by zerotier-one's web server.
+    redirect_uri: RedirectUrl,
+    /// The redirect URI of a pending loopback login, which replaces `redirect_uri`.
+    loopback_redirect: Option<RedirectUrl>,
+    session_store: Option<SessionStore>,
+    last_error: Option<SessionError>,
+    events: EventSink,
+    jwks: JwksCache,
+    /// Everything logged on behalf of this session happens in this span.
+    span: Span,
 
     url: Option<Url>,
-    csrf_token: Option<CsrfToken>,
-    nonce: Option<Nonce>,
-    pkce_verifier: Option<PkceCodeVerifier>,
+    /// The pushed request `url` points to, if PAR is used.
+    pushed: Option<PushedRequest>,
+    csrf_token: Option<Secret>,
+    nonce: Option<Secret>,
+    pkce_verifier: Option<Secret>,
 }
 
 impl Inner {
@@ -79,17 +184,266 @@ impl Inner {
     fn as_opt(&mut self) -> Option<&mut Inner> {
         Some(self)
     }
+
+    /// Writes the current tokens to the session store, if persistence is enabled.
+    fn save_session(&self) {
+        if let (Some(store), Some(refresh_token)) = (self.session_store.as_ref(), self.refresh_token.as_ref()) {
+            store.save(&StoredSession {
+                issuer: store.issuer().to_string(),
+                client_id: store.client_id().to_string(),
+                network_id: store.network_id().to_string(),
+                refresh_token: refresh_token.clone(),
+                access_token: self.access_token.clone(),
+                nonce: self.nonce.clone(),
+                exp_time: self.exp_time,
+                issued_time: self.issued_time,
+                central_exp_time: self.central_exp_time,
+                claims: self.claims.clone(),
+                dpop_key: match self.dpop.as_ref() {
+                    Some(k) if self.dpop_bound => Some(k.to_secret()),
+                    _ => None,
+                },
+            });
+        }
+    }
+
+    /// (Re)builds the OIDC client from the provider's metadata, our credentials and the
+    /// profile.  The client takes its secret only when it's created, so this is done
+    /// again whenever the credentials change.
+    fn configure_client(&mut self) {
+        let extra_meta = self.provider_meta.additional_metadata().clone();
+        self.client_auth.choose_method(
+            self.provider_meta.token_endpoint_auth_methods_supported(),
+            self.profile.token_endpoint_auth_method,
+        );
+        debug!(method = ?self.client_auth.method, "configuring client authentication");
+
+        let redirect = self
+            .loopback_redirect
+            .clone()
+            .unwrap_or_else(|| self.redirect_uri.clone());
+        let mut client = CoreClient::from_provider_metadata(
+            self.provider_meta.clone(),
+            ClientId::new(self.client_id.clone()),
+            self.client_auth.client_secret(),
+        )
+        .set_redirect_uri(redirect)
+        .set_auth_type(self.client_auth.method.auth_type());
+        if let Some(device_url) = extra_meta.device_authorization_endpoint {
+            client = client.set_device_authorization_uri(device_url);
+        }
+        if let Some(revocation_url) = extra_meta.revocation_endpoint {
+            client = client.set_revocation_uri(revocation_url);
+        }
+        self.oidc_client = Some(client);
+    }
+
+    /// Whether the IdP accepts proofs signed the way we sign them.
+    fn provider_supports_dpop(&self) -> bool {
+        match self
+            .provider_meta
+            .additional_metadata()
+            .dpop_signing_alg_values_supported
+            .as_ref()
+        {
+            Some(algs) => algs.iter().any(|a| a == DPOP_ALG),
+            None => false,
+        }
+    }
+
+    /// Gives the next session a new DPoP key, or none if DPoP is off or the IdP doesn't
+    /// support it.
+    fn reset_dpop(&mut self) {
+        self.dpop_bound = false;
+        self.dpop = if self.dpop_enabled && self.provider_supports_dpop() {
+            Some(Arc::new(DpopKey::generate()))
+        } else {
+            None
+        };
+    }
+
+    /// Generates a new PKCE verifier and authorization URL for the given state and nonce.
+    fn build_auth_url(&mut self, csrf_token: Secret, nonce: Secret) {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let pkce_verifier = Secret::new(pkce_verifier.secret().clone());
+        let r = self.oidc_client.as_ref().map(|c| {
+            let mut auth_builder = c
+                .authorize_url(
+                    AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+                    csrf_func(csrf_token.clone()),
+                    nonce_func(nonce.clone()),
+                )
+                .set_pkce_challenge(pkce_challenge);
+            for scope in self.profile.scopes() {
+                auth_builder = auth_builder.add_scope(scope);
+            }
+            // per-network parameters win over the provider profile's
+            let mut params = self.profile.extra_auth_params.clone();
+            params.extend(self.auth_params.clone());
+            if let Some(key) = self.dpop.as_ref() {
+                // binds the authorization code to our key (RFC 9449 section 10)
+                params.insert("dpop_jkt".to_string(), key.thumbprint());
+            }
+            params.extend(self.policy.auth_params());
+            for (name, value) in params {
+                auth_builder = auth_builder.add_extra_param(name, value);
+            }
+
+            auth_builder.url()
+        });
+
+        if let Some((url, _, _)) = r {
+            self.csrf_token = Some(csrf_token);
+            self.nonce = Some(nonce);
+            self.pkce_verifier = Some(pkce_verifier);
+            self.state = SessionState::AwaitingAuthorization;
+
+            let (endpoint, required) = self.par_endpoint();
+            if endpoint.is_some() || required {
+                // pushed once the URL is asked for, see `ZeroIDC::auth_url`
+                self.url = None;
+                self.pushed = Some(PushedRequest { source: url, expires: Instant::now() });
+            } else {
+                self.url = Some(url);
+                self.pushed = None;
+            }
+        }
+    }
+
+    /// The IdP's pushed authorization request endpoint, and whether we have to use it.
+    fn par_endpoint(&self) -> (Option<Url>, bool) {
+        let extra = self.provider_meta.additional_metadata();
+        let endpoint = extra
+            .pushed_authorization_request_endpoint
+            .as_ref()
+            .and_then(|e| match Url::parse(e) {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    warn!("invalid pushed_authorization_request_endpoint: {}", e);
+                    None
+                }
+            });
+        let required =
+            extra.require_pushed_authorization_requests || self.profile.require_pushed_authorization_requests;
+        (endpoint, required)
+    }
+
+    /// The authorization request (see [`par`]) to push before `url` can be handed out,
+    /// if it hasn't been pushed yet or its `request_uri` is about to expire.
+    fn pending_push(&self) -> Option<PendingPush> {
+        let pushed = self.pushed.as_ref()?;
+        if !pushed.is_stale() || self.state.is_running() {
+            return None;
+        }
+        Some(PendingPush {
+            http: self.http.clone(),
+            endpoint: self.par_endpoint().0,
+            authorization_endpoint: self.provider_meta.authorization_endpoint().url().clone(),
+            client_auth: self.client_auth.clone(),
+            source: pushed.source.clone(),
+        })
+    }
+
+    /// Points `url` at the request pushed for `source`.  Unless PAR is required, a
+    /// failed push falls back to `source` itself.
+    fn finish_push(&mut self, source: Url, res: Result<(Url, Instant), PushedAuthorizationError>) {
+        if self.pushed.as_ref().map(|p| &p.source) != Some(&source) {
+            debug!("authorization request changed while it was pushed. dropping the result");
+            return;
+        }
+
+        let (_, required) = self.par_endpoint();
+        match res {
+            Ok((url, expires)) => {
+                debug!("pushed authorization request");
+                self.url = Some(url);
+                self.pushed = Some(PushedRequest { source, expires });
+            }
+            Err(e) if required => {
+                error!("pushed authorization request failed: {}", e);
+                // tried again the next time the URL is asked for
+                self.url = None;
+                self.pushed = Some(PushedRequest { source, expires: Instant::now() });
+            }
+            Err(e) => {
+                warn!("pushed authorization request failed, using a regular one: {}", e);
+                self.url = Some(source);
+                self.pushed = None;
+            }
+        }
+    }
+
+    /// Rebuilds a pending authorization URL after its parameters changed.
+    fn rebuild_auth_url(&mut self) {
+        if self.state.is_running() {
+            return;
+        }
+        if let (Some(csrf), Some(nonce)) = (self.csrf_token.clone(), self.nonce.clone()) {
+            self.build_auth_url(csrf, nonce);
+        }
+    }
+
+    fn update_claims(&mut self, id_token: Option<&CoreIdToken>, claims: &CoreIdTokenClaims) {
+        self.claims = id_token.map(|t| SessionClaims::new(t, claims, &self.profile.claims));
+    }
+
+    /// When the current ID token should be refreshed.  If central's authorization runs
+    /// out first, that's when, unless it already has or refreshing doesn't extend it.
+    /// Never sooner than [`MIN_REFRESH_INTERVAL`] after the last refresh.
+    fn refresh_due(&self) -> SystemTime {
+        let offset = self.http.clock_offset().unwrap_or(0);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let exp = match self.central_exp_time {
+            central if central > now && self.follow_central_exp => central.min(self.exp_time),
+            _ => self.exp_time,
+        };
+        let due = self.timing.refresh_due(self.issued_time, exp, offset);
+        match self.last_refresh {
+            Some(last) => due.max(last + MIN_REFRESH_INTERVAL),
+            None => due,
+        }
+    }
+
+    /// The network the current login is for, from the state token.
+    fn network_id(&self) -> Option<String> {
+        let csrf_token = self.csrf_token.as_ref()?;
+        match csrf_token.expose().split('_').collect::<Vec<&str>>()[..] {
+            [_, network_id] => Some(network_id.to_string()),
+            _ => None,
+        }
+    }
+
+    fn clear_session(&self) {
+        if let Some(store) = self.session_store.as_ref() {
+            store.clear();
+        }
+    }
 }
 
-fn csrf_func(csrf_token: String) -> Box<dyn Fn() -> CsrfToken> {
-    Box::new(move || CsrfToken::new(csrf_token.to_string()))
+/// Authorization request parameters that zeroidc manages itself.
+const RESERVED_AUTH_PARAMS: &[&str] = &[
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "nonce",
+    "code_challenge",
+    "code_challenge_method",
+    "dpop_jkt",
+];
+
+fn csrf_func(csrf_token: Secret) -> Box<dyn Fn() -> CsrfToken> {
+    Box::new(move || CsrfToken::new(csrf_token.expose().to_string()))
 }
 
-fn nonce_func(nonce: String) -> Box<dyn Fn() -> Nonce> {
-    Box::new(move || Nonce::new(nonce.to_string()))
+fn nonce_func(nonce: Secret) -> Box<dyn Fn() -> Nonce> {
+    Box::new(move || Nonce::new(nonce.expose().to_string()))
 }
 
-#[cfg(debug_assertions)]
 fn systemtime_strftime<T>(dt: T, format: &str) -> String
 where
     T: Into<OffsetDateTime>,
@@ -119,541 +473,1017 @@ impl ZeroIDC {
         auth_ep: &str,
         local_web_port: u16,
     ) -> Result<ZeroIDC, ZeroIDCError> {
+        ZeroIDC::with_http_config(
+            issuer,
+            client_id,
+            provider,
+            auth_ep,
+            local_web_port,
+            &HttpConfig::default(),
+        )
+    }
+
+    /// Like [`ZeroIDC::new`], with the proxy, CAs, timeouts and user agent of
+    /// `http_config` applied to every request.
+    pub fn with_http_config(
+        issuer: &str,
+        client_id: &str,
+        provider: &str,
+        auth_ep: &str,
+        local_web_port: u16,
+        http_config: &HttpConfig,
+    ) -> Result<ZeroIDC, ZeroIDCError> {
+        debug!(
+            issuer,
+            client_id,
+            auth_endpoint = auth_ep,
+            local_web_port,
+            "creating sso client"
+        );
+        let iss = IssuerUrl::new(issuer.to_string())?;
+        let http = HttpClient::new(http_config)?;
+
+        let provider_meta = ZeroIDCProviderMetadata::discover(&iss, |req| http.request(req))?;
+        let extra_meta = provider_meta.additional_metadata().clone();
+        let jwks = JwksCache::new(
+            http.clone(),
+            provider_meta.jwks_uri().clone(),
+            ClientId::new(client_id.to_string()),
+            provider_meta.issuer().clone(),
+            Some(provider_meta.id_token_signing_alg_values_supported().clone()),
+            provider_meta.jwks().clone(),
+        );
+
+        let r = format!("http://localhost:{}/sso", local_web_port);
+        let redir_url = Url::parse(&r)?;
+
+        let redirect = RedirectUrl::new(redir_url.to_string())?;
+
+        let profile = ProviderProfile::for_provider(provider);
+        let client_auth = ClientAuth {
+            client_id: client_id.to_string(),
+            audience: match provider_meta.token_endpoint() {
+                Some(t) => t.to_string(),
+                None => issuer.to_string(),
+            },
+            ..Default::default()
+        };
         let idc = ZeroIDC {
             inner: Arc::new(Mutex::new(Inner {
-                running: false,
+                state: SessionState::Idle,
+                issuer: issuer.to_string(),
+                client_id: client_id.to_string(),
                 provider: provider.to_string(),
+                profile,
+                auth_params: BTreeMap::new(),
                 auth_endpoint: auth_ep.to_string(),
-                oidc_thread: None,
+                session_id: next_session_id(),
+                http,
+                provider_meta,
                 oidc_client: None,
+                client_auth,
+                dpop_enabled: false,
+                dpop: None,
+                dpop_bound: false,
                 access_token: None,
                 refresh_token: None,
+                id_token: None,
+                end_session_endpoint: extra_meta.end_session_endpoint.clone(),
                 exp_time: 0,
-                kick: false,
+                issued_time: 0,
+                central_exp_time: 0,
+                follow_central_exp: true,
+                timing: TimingConfig::default(),
+                policy: AdmissionPolicy::default(),
+                refresh_nonce: None,
+                refresh_failures: 0,
+                last_refresh: None,
+                subject: None,
+                claims: None,
+                device_pending: false,
+                loopback_pending: false,
+                redirect_uri: redirect,
+                loopback_redirect: None,
+                session_store: None,
+                last_error: None,
+                events: EventSink::default(),
+                jwks,
+                span: info_span!("sso", network = tracing::field::Empty),
 
                 url: None,
+                pushed: None,
                 csrf_token: None,
                 nonce: None,
                 pkce_verifier: None,
             })),
         };
 
-        println!(
-            "issuer: {}, client_id: {}, auth_endpoint: {}, local_web_port: {}",
-            issuer, client_id, auth_ep, local_web_port
-        );
-        let iss = IssuerUrl::new(issuer.to_string())?;
+        idc.inner.lock().unwrap().configure_client();
 
-        let provider_meta = CoreProviderMetadata::discover(&iss, http_client)?;
+        Ok(idc)
+    }
 
-        let r = format!("http://localhost:{}/sso", local_web_port);
-        let redir_url = Url::parse(&r)?;
+    fn kick_refresh_thread(&mut self) {
+        let (running, id) = {
+            let i = self.inner.lock().unwrap();
+            (i.state.is_running(), i.session_id)
+        };
+        if running {
+            debug!("refresh thread kicked");
+            scheduler().schedule(id, &self.inner, SystemTime::now());
+        }
+    }
 
-        let redirect = RedirectUrl::new(redir_url.to_string())?;
+    fn start(&mut self) {
+        let (id, due) = {
+            let mut i = self.inner.lock().unwrap();
+            if i.state.is_running() {
+                return;
+            }
+            i.state = SessionState::Active;
 
-        idc.inner.lock().unwrap().oidc_client = Some(
-            CoreClient::from_provider_metadata(provider_meta, ClientId::new(client_id.to_string()), None)
-                .set_redirect_uri(redirect)
-                .set_auth_type(openidconnect::AuthType::RequestBody),
-        );
+            // Keep a copy of the initial nonce used to get the tokens
+            // Will be needed later when verifying the responses from refresh tokens
+            i.refresh_nonce = i.nonce.clone();
+            i.refresh_failures = 0;
+            (i.session_id, i.refresh_due())
+        };
 
-        Ok(idc)
+        scheduler().schedule(id, &self.inner, due);
     }
 
-    fn kick_refresh_thread(&mut self) {
-        let local = Arc::clone(&self.inner);
-        local.lock().unwrap().kick = true;
+    /// The span this session logs in.
+    fn span(&self) -> Span {
+        self.inner.lock().unwrap().span.clone()
     }
 
-    fn start(&mut self) {
-        let local = Arc::clone(&self.inner);
+    /// Tags everything this session logs with the network it belongs to.
+    pub fn set_network_id(&mut self, network_id: &str) {
+        self.span().record("network", network_id);
+    }
 
-        if !local.lock().unwrap().running {
-            let inner_local = Arc::clone(&self.inner);
-            local.lock().unwrap().oidc_thread = Some(spawn(move || {
-                inner_local.lock().unwrap().running = true;
-                let mut running = true;
-
-                // Keep a copy of the initial nonce used to get the tokens
-                // Will be needed later when verifying the responses from refresh tokens
-                let nonce = inner_local.lock().unwrap().nonce.clone();
-
-                while running {
-                    let exp = UNIX_EPOCH + Duration::from_secs(inner_local.lock().unwrap().exp_time);
-                    let now = SystemTime::now();
-
-                    #[cfg(debug_assertions)]
-                    {
-                        println!(
-                            "refresh token thread tick, now: {}, exp: {}",
-                            systemtime_strftime(now, "[year]-[month]-[day] [hour]:[minute]:[second]"),
-                            systemtime_strftime(exp, "[year]-[month]-[day] [hour]:[minute]:[second]")
-                        );
-                    }
-                    let refresh_token = inner_local.lock().unwrap().refresh_token.clone();
-
-                    if let Some(refresh_token) = refresh_token {
-                        let should_kick = inner_local.lock().unwrap().kick;
-                        if now >= (exp - Duration::from_secs(30)) || should_kick {
-                            if should_kick {
-                                #[cfg(debug_assertions)]
-                                {
-                                    println!("refresh thread kicked");
-                                }
-                                inner_local.lock().unwrap().kick = false;
-                            }
-
-                            #[cfg(debug_assertions)]
-                            {
-                                println!("Refresh Token: {}", refresh_token.secret());
-                            }
-
-                            let token_response = inner_local.lock().unwrap().oidc_client.as_ref().map(|c| {
-                                let res = c.exchange_refresh_token(&refresh_token).request(http_client);
-
-                                res
-                            });
-
-                            if let Some(res) = token_response {
-                                match res {
-                                    Ok(res) => {
-                                        match res.id_token() {
-                                            Some(id_token) => {
-                                                let n = match nonce.clone() {
-                                                    Some(n) => n.secret().to_string(),
-                                                    None => "".to_string(),
-                                                };
-
-                                                let params = [
-                                                    ("id_token", id_token.to_string()),
-                                                    ("state", "refresh".to_string()),
-                                                    ("extra_nonce", n),
-                                                ];
-                                                #[cfg(debug_assertions)]
-                                                {
-                                                    println!("New ID token: {}", id_token.to_string());
-                                                }
-                                                let client = reqwest::blocking::Client::new();
-                                                let r = client
-                                                    .post(inner_local.lock().unwrap().auth_endpoint.clone())
-                                                    .form(&params)
-                                                    .send();
-
-                                                match r {
-                                                    Ok(r) => {
-                                                        if r.status().is_success() {
-                                                            #[cfg(debug_assertions)]
-                                                            {
-                                                                println!("hit url: {}", r.url().as_str());
-                                                                println!("status: {}", r.status());
-                                                            }
-
-                                                            let access_token = res.access_token();
-                                                            let idt = &id_token.to_string();
-
-                                                            let t: Result<
-                                                                Token<jwt::Header, jwt::Claims, jwt::Unverified<'_>>,
-                                                                jwt::Error,
-                                                            > = Token::parse_unverified(idt);
-
-                                                            if let Ok(t) = t {
-                                                                let claims = t.claims().registered.clone();
-                                                                match claims.expiration {
-                                                                    Some(exp) => {
-                                                                        println!("exp: {}", exp);
-                                                                        inner_local.lock().unwrap().exp_time = exp;
-                                                                    }
-                                                                    None => {
-                                                                        panic!("expiration is None.  This shouldn't happen")
-                                                                    }
-                                                                }
-                                                            } else {
-                                                                panic!("error parsing claims");
-                                                            }
-
-                                                            inner_local.lock().unwrap().access_token =
-                                                                Some(access_token.clone());
-                                                            if let Some(t) = res.refresh_token() {
-                                                                // println!("New Refresh Token: {}", t.secret());
-                                                                inner_local.lock().unwrap().refresh_token =
-                                                                    Some(t.clone());
-                                                            }
-                                                            #[cfg(debug_assertions)]
-                                                            {
-                                                                println!("Central post succeeded");
-                                                            }
-                                                        } else {
-                                                            println!("Central post failed: {}", r.status());
-                                                            println!("hit url: {}", r.url().as_str());
-                                                            println!("Status: {}", r.status());
-                                                            if let Ok(body) = r.bytes() {
-                                                                if let Ok(body) = std::str::from_utf8(&body) {
-                                                                    println!("Body: {}", body);
-                                                                }
-                                                            }
-
-                                                            inner_local.lock().unwrap().exp_time = 0;
-                                                            inner_local.lock().unwrap().running = false;
-                                                        }
-                                                    }
-                                                    Err(e) => {
-                                                        println!("Central post failed: {}", e);
-                                                        println!("hit url: {}", e.url().unwrap().as_str());
-                                                        println!("Status: {}", e.status().unwrap());
-                                                        inner_local.lock().unwrap().exp_time = 0;
-                                                        inner_local.lock().unwrap().running = false;
-                                                    }
-                                                }
-                                            }
-                                            None => {
-                                                println!("no id token?!?");
-                                                inner_local.lock().unwrap().exp_time = 0;
-                                                inner_local.lock().unwrap().running = false;
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        println!("token error: {}", e);
-                                        inner_local.lock().unwrap().exp_time = 0;
-                                        inner_local.lock().unwrap().running = false;
-                                    }
-                                }
-                            } else {
-                                println!("token response??");
-                                inner_local.lock().unwrap().exp_time = 0;
-                                inner_local.lock().unwrap().running = false;
-                            }
-                        } else {
-                            #[cfg(debug_assertions)]
-                            println!("waiting to refresh");
+    /// Enables saving the session for `network_id` under `home`, and resumes a previously
+    /// saved session if there is one.  Returns true if a session was resumed.
+    pub fn enable_session_persistence(&mut self, home: &Path, network_id: &str) -> bool {
+        let resumed = {
+            let mut i = self.inner.lock().unwrap();
+            let store = SessionStore::new(home, &i.issuer, &i.client_id, network_id);
+            let stored = store.load();
+            i.session_store = Some(store);
+
+            match stored {
+                Some(s) if !s.refresh_token.is_empty() => {
+                    info!(network = network_id, "resuming stored sso session");
+                    i.refresh_token = Some(s.refresh_token.clone());
+                    i.access_token = s.access_token.clone();
+                    i.nonce = s.nonce.clone();
+                    i.exp_time = s.exp_time;
+                    i.issued_time = s.issued_time;
+                    i.central_exp_time = s.central_exp_time;
+                    i.claims = s.claims.clone();
+                    if let Some(key) = s.dpop_key.as_ref() {
+                        i.dpop = DpopKey::from_secret(key).map(Arc::new);
+                        i.dpop_bound = i.dpop.is_some();
+                        if !i.dpop_bound {
+                            warn!("stored sso session has an unreadable DPoP key");
                         }
-                    } else {
-                        println!("no refresh token?");
-                        inner_local.lock().unwrap().exp_time = 0;
-                        inner_local.lock().unwrap().running = false;
-                    }
-
-                    sleep(Duration::from_secs(1));
-                    {
-                        running = inner_local.lock().unwrap().running;
                     }
+                    true
                 }
-                // end run loop
+                _ => false,
+            }
+        };
 
-                println!("thread done!");
-                inner_local.lock().unwrap().running = false;
-                println!("set idc thread running flag to false");
-            }));
+        if resumed {
+            // refresh right away so central learns about us and we find out
+            // whether the refresh token is still good
+            self.start();
+            self.kick_refresh_thread();
         }
+        resumed
     }
 
     pub fn stop(&mut self) {
-        let local = self.inner.clone();
-        if self.is_running() {
-            local.lock().unwrap().running = false;
+        let id = {
+            let mut i = self.inner.lock().unwrap();
+            i.state = SessionState::Idle;
+            i.device_pending = false;
+            i.loopback_pending = false;
+            i.session_id
+        };
+        scheduler().cancel(id);
+    }
+
+    /// Ends the session: revokes the refresh token at the IdP's revocation endpoint,
+    /// ends the IdP session through its end_session_endpoint and wipes all local token
+    /// state, including the stored session.  Remote failures are logged and otherwise
+    /// ignored, so local state is always cleared.  Returns true if every remote call
+    /// that was attempted succeeded.
+    pub fn logout(&mut self) -> bool {
+        let _span = self.span().entered();
+        self.stop();
+
+        let (http, client, client_auth, client_id, end_session, id_token, refresh_token) = {
+            let mut i = self.inner.lock().unwrap();
+            i.state = SessionState::LoggedOut;
+            let refresh_token = i.refresh_token.take();
+            let id_token = i.id_token.take();
+            i.access_token = None;
+            i.exp_time = 0;
+            i.central_exp_time = 0;
+            i.claims = None;
+            i.pkce_verifier = None;
+            i.url = None;
+            i.pushed = None;
+            i.clear_session();
+            i.reset_dpop();
+            (
+                i.http.clone(),
+                i.oidc_client.clone(),
+                i.client_auth.clone(),
+                i.client_id.clone(),
+                i.end_session_endpoint.clone(),
+                id_token,
+                refresh_token,
+            )
+        };
+
+        let mut ok = true;
+
+        if let (Some(client), Some(refresh_token)) = (client, refresh_token) {
+            // a ConfigurationError here just means the IdP has no revocation endpoint
+            let refresh_token = RefreshToken::new(refresh_token.expose().to_string());
+            if let Ok(mut req) = client.revoke_token(CoreRevocableToken::RefreshToken(refresh_token)) {
+                let res = match client_auth.assertion() {
+                    Ok(assertion) => {
+                        if let Some(a) = assertion.as_ref() {
+                            req = req
+                                .add_extra_param("client_assertion_type", JWT_BEARER_ASSERTION)
+                                .add_extra_param("client_assertion", a.expose());
+                        }
+                        req.request(|req| http.request(req)).map_err(|e| e.to_string())
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = res {
+                    warn!("error revoking refresh token: {}", e);
+                    ok = false;
+                }
+            }
         }
+
+        if let Some(end_session) = end_session {
+            let id_token = id_token.and_then(|t| CoreIdToken::from_str(t.expose()).ok());
+            let mut req = LogoutRequest::from(end_session).set_client_id(ClientId::new(client_id));
+            if let Some(id_token) = id_token.as_ref() {
+                req = req.set_id_token_hint(id_token);
+            }
+
+            match http.client().get(req.http_get_url()).send() {
+                Ok(r) if r.status().is_success() || r.status().is_redirection() => {}
+                Ok(r) => {
+                    warn!("end session request failed: {}", r.status());
+                    ok = false;
+                }
+                Err(e) => {
+                    warn!("end session request failed: {}", e);
+                    ok = false;
+                }
+            }
+        }
+
+        ok
     }
 
     pub fn is_running(&mut self) -> bool {
         let local = Arc::clone(&self.inner);
-        let running = local.lock().unwrap().running;
+        let running = local.lock().unwrap().state.is_running();
 
         running
     }
 
+    pub fn state(&self) -> SessionState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// A snapshot of the session for status displays.
+    pub fn status(&self) -> SessionStatus {
+        let i = self.inner.lock().unwrap();
+        let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok();
+        SessionStatus {
+            state: i.state,
+            last_error: i.last_error.as_ref().map(|e| e.to_string()),
+            last_refresh: i.last_refresh.and_then(secs),
+            expires: if i.exp_time > 0 {
+                Some(i.exp_time)
+            } else {
+                None
+            },
+            issuer: i.issuer.clone(),
+            subject: i.subject.clone(),
+            central_expires: if i.central_exp_time > 0 {
+                Some(i.central_exp_time)
+            } else {
+                None
+            },
+            dpop: i.dpop_bound,
+            clock_offset: i.http.clock_offset(),
+        }
+    }
+
+    /// The claims of the logged in user, if there is one.
+    pub fn claims(&self) -> Option<SessionClaims> {
+        self.inner.lock().unwrap().claims.clone()
+    }
+
     pub fn get_exp_time(&mut self) -> u64 {
         return self.inner.lock().unwrap().exp_time;
     }
 
+    /// The error that ended the last session, if any.
+    pub fn last_error(&self) -> Option<String> {
+        self.inner.lock().unwrap().last_error.as_ref().map(|e| e.to_string())
+    }
+
+    /// Sets the handler that session events are delivered to, or removes it.  Once this
+    /// returns, the old handler won't be called again.
+    pub fn set_event_handler(&mut self, handler: Option<EventHandler>) {
+        let sink = self.inner.lock().unwrap().events.clone();
+        sink.set(handler);
+    }
+
     pub fn set_nonce_and_csrf(&mut self, csrf_token: String, nonce: String) {
+        let (csrf_token, nonce) = (Secret::new(csrf_token), Secret::new(nonce));
         let local = Arc::clone(&self.inner);
-        (*local.lock().expect("can't lock inner")).as_opt().map(|i| {
-            if i.running {
-                println!("refresh thread running. not setting new nonce or csrf");
+        let mut guard = local.lock().expect("can't lock inner");
+        if let Some(i) = (*guard).as_opt() {
+            if i.state.is_running() {
+                debug!("refresh thread running. not setting new nonce or csrf");
                 return;
             }
 
-            let need_verifier = matches!(i.pkce_verifier, None);
+            let need_verifier = i.pkce_verifier.is_none();
 
-            let csrf_diff = if let Some(csrf) = i.csrf_token.clone() {
-                *csrf.secret() != csrf_token
+            let csrf_diff = if let Some(csrf) = i.csrf_token.as_ref() {
+                !csrf.matches(csrf_token.expose())
             } else {
                 false
             };
 
-            let nonce_diff = if let Some(n) = i.nonce.clone() {
-                *n.secret() != nonce
+            let nonce_diff = if let Some(n) = i.nonce.as_ref() {
+                !n.matches(nonce.expose())
             } else {
                 false
             };
 
             if need_verifier || csrf_diff || nonce_diff {
-                let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
-                let r = i.oidc_client.as_ref().map(|c| {
-                    let mut auth_builder = c
-                        .authorize_url(
-                            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-                            csrf_func(csrf_token),
-                            nonce_func(nonce),
-                        )
-                        .set_pkce_challenge(pkce_challenge);
-                    match i.provider.as_str() {
-                        "auth0" => {
-                            auth_builder = auth_builder
-                                .add_scope(Scope::new("profile".to_string()))
-                                .add_scope(Scope::new("email".to_string()))
-                                .add_scope(Scope::new("offline_access".to_string()));
-                        }
-                        "okta" => {
-                            auth_builder = auth_builder
-                                .add_scope(Scope::new("profile".to_string()))
-                                .add_scope(Scope::new("email".to_string()))
-                                .add_scope(Scope::new("groups".to_string()))
-                                .add_scope(Scope::new("offline_access".to_string()));
-                        }
-                        "keycloak" => {
-                            auth_builder = auth_builder
-                                .add_scope(Scope::new("profile".to_string()))
-                                .add_scope(Scope::new("email".to_string()));
-                        }
-                        "onelogin" => {
-                            auth_builder = auth_builder
-                                .add_scope(Scope::new("profile".to_string()))
-                                .add_scope(Scope::new("email".to_string()))
-                                .add_scope(Scope::new("groups".to_string()))
-                        }
-                        "default" => {
-                            auth_builder = auth_builder
-                                .add_scope(Scope::new("profile".to_string()))
-                                .add_scope(Scope::new("email".to_string()))
-                                .add_scope(Scope::new("offline_access".to_string()));
-                        }
-                        _ => {
-                            auth_builder = auth_builder
-                                .add_scope(Scope::new("profile".to_string()))
-                                .add_scope(Scope::new("email".to_string()))
-                                .add_scope(Scope::new("offline_access".to_string()));
-                        }
-                    }
+                i.build_auth_url(csrf_token, nonce);
+            }
+        }
+    }
 
-                    auth_builder.url()
-                });
+    /// Switches to the profile for our provider from `json` (see [`profile`]), if it has
+    /// one.  Returns whether a profile was found.
+    pub fn load_provider_profiles(&mut self, json: &str) -> Result<bool, serde_json::Error> {
+        let profiles = parse_profiles(json)?;
+        let mut i = self.inner.lock().unwrap();
+        let profile = match profiles.get(&i.provider) {
+            Some(p) => p.clone(),
+            None => return Ok(false),
+        };
 
-                if let Some(r) = r {
-                    i.url = Some(r.0);
-                    i.csrf_token = Some(r.1);
-                    i.nonce = Some(r.2);
-                    i.pkce_verifier = Some(pkce_verifier);
-                }
+        i.profile = profile;
+        i.configure_client();
+
+        i.rebuild_auth_url();
+
+        Ok(true)
+    }
+
+    /// Sets the clock skew tolerance and refresh lead time (see [`timing`]).  An active
+    /// session's next refresh is rescheduled to match.
+    pub fn set_timing(&mut self, timing: TimingConfig) {
+        let reschedule = {
+            let mut i = self.inner.lock().unwrap();
+            i.jwks.set_clock_skew(timing.clock_skew());
+            i.timing = timing;
+            match i.state {
+                SessionState::Active => Some((i.session_id, i.refresh_due())),
+                _ => None,
             }
-        });
+        };
+        if let Some((id, due)) = reschedule {
+            scheduler().schedule(id, &self.inner, due);
+        }
     }
 
+    /// Sets the policy ID tokens have to meet before they're posted to central (see
+    /// [`policy`]).  Applies from the next login or refresh on.
+    pub fn set_admission_policy(&mut self, policy: AdmissionPolicy) {
+        let mut i = self.inner.lock().unwrap();
+        i.policy = policy;
+        i.rebuild_auth_url();
+    }
+
+    /// Gives the client a secret for `client_secret_basic` or `client_secret_post`, or
+    /// takes it away if `secret` is `None`.
+    pub fn set_client_secret(&mut self, secret: Option<&str>) {
+        let mut i = self.inner.lock().unwrap();
+        i.client_auth.secret = secret.map(|s| Secret::new(s.to_string()));
+        i.configure_client();
+    }
+
+    /// Gives the client a private key for `private_key_jwt`, or takes it away if `key`
+    /// is `None`.
+    pub fn set_client_key(&mut self, key: Option<ClientKey>) {
+        let mut i = self.inner.lock().unwrap();
+        i.client_auth.key = key.map(Arc::new);
+        i.configure_client();
+    }
+
+    /// Turns DPoP on or off for new sessions.  A session that is already running keeps
+    /// the tokens it has, bound or not.  Returns false if DPoP was turned on but the IdP
+    /// doesn't support it, in which case sessions keep using bearer tokens.
+    pub fn set_dpop(&mut self, enabled: bool) -> bool {
+        let mut i = self.inner.lock().unwrap();
+        i.dpop_enabled = enabled;
+        let supported = i.provider_supports_dpop();
+        if enabled && !supported {
+            warn!("identity provider doesn't support {} DPoP proofs", DPOP_ALG);
+        }
+        if !i.state.is_running() {
+            i.reset_dpop();
+            i.rebuild_auth_url();
+        }
+        !enabled || supported
+    }
+
+    /// Sets an extra parameter for this network's authorization URL, e.g. `login_hint`,
+    /// `prompt`, `max_age`, `acr_values` or `domain_hint`.  A `None` value removes it.
+    /// Parameters zeroidc sets itself can't be overridden.
+    pub fn set_auth_param(&mut self, name: &str, value: Option<&str>) -> bool {
+        if RESERVED_AUTH_PARAMS.contains(&name) {
+            warn!("not overriding reserved authorization parameter {}", name);
+            return false;
+        }
+
+        let mut i = self.inner.lock().unwrap();
+        match value {
+            Some(v) => i.auth_params.insert(name.to_string(), v.to_string()),
+            None => i.auth_params.remove(name),
+        };
+        i.rebuild_auth_url();
+
+        true
+    }
+
+    /// The URL to send the user's browser to.  A pushed authorization request that's
+    /// missing or about to expire is pushed first, without the session locked, so a
+    /// slow IdP doesn't hold up everything else.
     pub fn auth_url(&self) -> String {
-        let url = (*self.inner.lock().expect("can't lock inner"))
-            .as_opt()
-            .map(|i| match i.url.clone() {
-                Some(u) => u.to_string(),
-                _ => "".to_string(),
-            });
+        let pending = self.inner.lock().expect("can't lock inner").pending_push();
+        let pushed = pending.map(|p| {
+            debug!("pushing authorization request");
+            let res = p.push();
+            (p.source, res)
+        });
 
-        match url {
-            Some(url) => url,
+        let mut i = self.inner.lock().expect("can't lock inner");
+        if let Some((source, res)) = pushed {
+            i.finish_push(source, res);
+        }
+        match i.url.as_ref() {
+            Some(url) => url.to_string(),
             None => "".to_string(),
         }
     }
 
-    pub fn do_token_exchange(&mut self, code: &str) -> Result<String, SSOExchangeError> {
-        let local = Arc::clone(&self.inner);
-        let mut should_start = false;
-        let res = (*local.lock().unwrap()).as_opt().map(|i| {
-            if let Some(verifier) = i.pkce_verifier.take() {
-                let token_response = i.oidc_client.as_ref().map(|c| {
-                    println!("auth code: {}", code);
-
-                    let r = c
-                        .exchange_code(AuthorizationCode::new(code.to_string()))
-                        .set_pkce_verifier(verifier)
-                        .request(http_client);
-
-                    // validate the token hashes
-                    match r {
-                        Ok(res) => {
-                            let n = match i.nonce.clone() {
-                                Some(n) => n,
-                                None => {
-                                    println!("no nonce");
-                                    i.running = false;
-                                    return None;
-                                }
-                            };
-
-                            let id = match res.id_token() {
-                                Some(t) => t,
-                                None => {
-                                    println!("no id token");
-                                    i.running = false;
-                                    return None;
-                                }
-                            };
-
-                            let claims = match id.claims(&c.id_token_verifier(), &n) {
-                                Ok(c) => c,
-                                Err(_e) => {
-                                    println!("no claims");
-                                    i.running = false;
-                                    return None;
-                                }
-                            };
-
-                            let signing_algo = match id.signing_alg() {
-                                Ok(s) => s,
-                                Err(_) => {
-                                    println!("no signing algorithm");
-                                    i.running = false;
-                                    return None;
-                                }
-                            };
-
-                            if let Some(expected_hash) = claims.access_token_hash() {
-                                let actual_hash = match AccessTokenHash::from_token(res.access_token(), &signing_algo) {
-                                    Ok(h) => h,
-                                    Err(e) => {
-                                        println!("Error hashing access token: {}", e);
-                                        i.running = false;
-                                        return None;
-                                    }
-                                };
-
-                                if actual_hash != *expected_hash {
-                                    println!("token hash error");
-                                    i.running = false;
-                                    return None;
-                                }
-                            }
-                            Some(res)
-                        }
-                        Err(e) => {
-                            println!("token response error: {:?}", e.to_string());
-                            println!("\t {:?}", e.source());
-                            i.running = false;
-                            None
-                        }
-                    }
-                });
+    pub fn do_token_exchange(&mut self, code: &str) -> Result<CentralAuthResponse, SSOExchangeError> {
+        let _span = self.span().entered();
+        let res = {
+            let mut i = self.inner.lock().unwrap();
+            let res = exchange_code(&mut i, code);
+            if res.is_err() {
+                i.state = SessionState::Idle;
+            }
+            res
+        };
 
-                if let Some(Some(tok)) = token_response {
-                    let id_token = tok.id_token().unwrap();
-                    #[cfg(debug_assertions)]
-                    {
-                        println!("ID token: {}", id_token.to_string());
-                    }
+        // Sessions without a refresh token are started too, so they end with an
+        // event when the ID token runs out rather than silently
+        let central = res?;
+        self.start();
+        Ok(central)
+    }
+}
 
-                    let mut split = "".to_string();
-                    if let Some(tok) = i.csrf_token.clone() {
-                        split = tok.secret().to_owned();
-                    }
+/// Exchanges an authorization code for tokens and posts the ID token to central.
+/// Returns central's response.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn exchange_code(i: &mut Inner, code: &str) -> Result<CentralAuthResponse, SSOExchangeError> {
+    let verifier = i
+        .pkce_verifier
+        .take()
+        .ok_or_else(|| SSOExchangeError::InvalidState("invalid pkce verifier".to_string()))?;
+    let client = i
+        .oidc_client
+        .as_ref()
+        .ok_or_else(|| SSOExchangeError::InvalidState("no oidc client".to_string()))?;
+    let assertion = i.client_auth.assertion()?;
+    let dpop = i.dpop.clone();
+    let http = i.http.clone();
+    debug!("exchanging authorization code");
+    i.state = SessionState::Exchanging;
+
+    let mut req = client
+        .exchange_code(AuthorizationCode::new(code.to_string()))
+        .set_pkce_verifier(PkceCodeVerifier::new(verifier.expose().to_string()));
+    if let Some(a) = assertion.as_ref() {
+        req = req
+            .add_extra_param("client_assertion_type", JWT_BEARER_ASSERTION)
+            .add_extra_param("client_assertion", a.expose());
+    }
+    let res = req
+        .request(|req| dpop::request(dpop.as_deref(), req, |req| http.request(req)))
+        .map_err(|e| {
+            warn!("token exchange failed: {}", e);
+            if let Some(source) = e.source() {
+                debug!("caused by: {}", source);
+            }
+            let e = SSOExchangeError::from_token_error(e);
+            if let SSOExchangeError::Session(se) = &e {
+                i.last_error = Some(se.clone());
+            }
+            e
+        })?;
+
+    // validate the token hashes
+    let n = i
+        .nonce
+        .clone()
+        .ok_or_else(|| SSOExchangeError::InvalidState("no nonce".to_string()))?;
+    let nonce_check = move |got: Option<&Nonce>| match got {
+        Some(got) if n.matches(got.secret()) => Ok(()),
+        Some(_) => Err("nonce mismatch".to_string()),
+        None => Err("missing nonce claim".to_string()),
+    };
+    let (tok, claims) = verify_token_response(&mut i.jwks, res, nonce_check).map_err(|e| {
+        warn!("token verification failed: {}", e);
+        i.last_error = Some(e.clone());
+        e
+    })?;
+
+    post_token_to_central(i, &tok, &claims)
+}
 
-                    let split = split.split('_').collect::<Vec<&str>>();
-
-                    if split.len() == 2 {
-                        let params = [("id_token", id_token.to_string()), ("state", split[0].to_string())];
-                        let client = reqwest::blocking::Client::new();
-                        let res = client.post(i.auth_endpoint.clone()).form(&params).send();
-
-                        match res {
-                            Ok(res) => {
-                                if res.status() == 200 {
-                                    #[cfg(debug_assertions)]
-                                    {
-                                        println!("hit url: {}", res.url().as_str());
-                                        println!("Status: {}", res.status());
-                                    }
-
-                                    let idt = &id_token.to_string();
-
-                                    let t: Result<Token<jwt::Header, jwt::Claims, jwt::Unverified<'_>>, jwt::Error> =
-                                        Token::parse_unverified(idt);
-
-                                    if let Ok(t) = t {
-                                        let claims = t.claims().registered.clone();
-                                        match claims.expiration {
-                                            Some(exp) => {
-                                                i.exp_time = exp;
-                                                println!("Set exp time to: {:?}", i.exp_time);
-                                            }
-                                            None => {
-                                                panic!("expiration is None.  This shouldn't happen");
-                                            }
-                                        }
-                                    } else {
-                                        panic!("Failed to parse token");
-                                    }
-
-                                    i.access_token = Some(tok.access_token().clone());
-                                    if let Some(t) = tok.refresh_token() {
-                                        i.refresh_token = Some(t.clone());
-                                        should_start = true;
-                                    }
-                                    #[cfg(debug_assertions)]
-                                    {
-                                        let access_token = tok.access_token();
-                                        println!("Access Token: {}", access_token.secret());
-
-                                        let refresh_token = tok.refresh_token();
-                                        println!("Refresh Token: {}", refresh_token.unwrap().secret());
-                                    }
-
-                                    let bytes = match res.bytes() {
-                                        Ok(bytes) => bytes,
-                                        Err(_) => Bytes::from(""),
-                                    };
-
-                                    let bytes = match from_utf8(bytes.as_ref()) {
-                                        Ok(bytes) => bytes.to_string(),
-                                        Err(_) => "".to_string(),
-                                    };
-
-                                    Ok(bytes)
-                                } else if res.status() == 402 {
-                                    i.running = false;
-                                    Err(SSOExchangeError::new(
-                                        "additional license seats required. Please contact your network administrator."
-                                            .to_string(),
-                                    ))
-                                } else {
-                                    i.running = false;
-                                    Err(SSOExchangeError::new("error from central endpoint".to_string()))
-                                }
-                            }
-                            Err(res) => {
-                                println!("error result: {}", res);
-                                println!("hit url: {}", i.auth_endpoint.clone());
-                                println!("Post error: {}", res);
-                                i.exp_time = 0;
-                                i.running = false;
-                                Err(SSOExchangeError::new("error from central endpoint".to_string()))
-                            }
-                        }
-                    } else {
-                        i.running = false;
-                        Err(SSOExchangeError::new("error splitting state token".to_string()))
+/// Marks a session as over after its tokens can no longer be refreshed.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn end_session(i: &mut Inner) {
+    i.exp_time = 0;
+    i.central_exp_time = 0;
+    i.claims = None;
+    i.state = SessionState::Expired;
+    i.clear_session();
+    i.reset_dpop();
+}
+
+/// Refreshes the tokens of one session and posts the new ID token to central.  Runs on
+/// a thread started by the scheduler.  Returns when the next refresh is due, or `None`
+/// once the session has ended.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn refresh_session(inner: &Arc<Mutex<Inner>>) -> Option<SystemTime> {
+    let (sink, span) = {
+        let i = inner.lock().unwrap();
+        (i.events.clone(), i.span.clone())
+    };
+    let _span = span.entered();
+    let mut events = Vec::new();
+    let next = refresh_session_inner(inner, &mut events);
+    sink.deliver(events);
+    next
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn refresh_session_inner(inner: &Arc<Mutex<Inner>>, events: &mut Vec<(ZeroIDCEvent, Value)>) -> Option<SystemTime> {
+    let (client, client_auth, dpop, refresh_token, nonce, auth_endpoint) = {
+        let mut i = inner.lock().unwrap();
+        if !i.state.is_running() {
+            return None;
+        }
+        i.state = SessionState::Refreshing;
+
+        debug!(
+            "refreshing tokens, now: {}, exp: {}",
+            systemtime_strftime(SystemTime::now(), "[year]-[month]-[day] [hour]:[minute]:[second]"),
+            systemtime_strftime(
+                UNIX_EPOCH + Duration::from_secs(i.exp_time),
+                "[year]-[month]-[day] [hour]:[minute]:[second]"
+            )
+        );
+
+        let refresh_token = match i.refresh_token.clone() {
+            Some(t) => t,
+            None => {
+                warn!("no refresh token. ending session");
+                end_session(&mut i);
+                events.push((ZeroIDCEvent::ReauthRequired, json!({ "reason": "no refresh token" })));
+                return None;
+            }
+        };
+        let client = match i.oidc_client.clone() {
+            Some(c) => c,
+            None => {
+                error!("no oidc client. ending session");
+                end_session(&mut i);
+                events.push((ZeroIDCEvent::ReauthRequired, json!({ "reason": "no oidc client" })));
+                return None;
+            }
+        };
+
+        (
+            client,
+            i.client_auth.clone(),
+            i.dpop.clone(),
+            refresh_token,
+            i.refresh_nonce.clone(),
+            i.auth_endpoint.clone(),
+        )
+    };
+
+    let outcome = try_refresh(
+        inner,
+        &client,
+        &client_auth,
+        dpop.as_deref(),
+        &refresh_token,
+        nonce,
+        auth_endpoint,
+    );
+
+    let mut i = inner.lock().unwrap();
+    if i.state != SessionState::Refreshing {
+        // stopped while we were refreshing
+        return None;
+    }
+
+    match outcome {
+        Ok((res, claims, central)) => {
+            let exp = claims.expiration().timestamp().max(0) as u64;
+            i.state = SessionState::Active;
+            i.last_refresh = Some(SystemTime::now());
+            i.subject = Some(claims.subject().to_string());
+            i.exp_time = exp;
+            i.issued_time = claims.issue_time().timestamp().max(0) as u64;
+            let central_exp = central.exp_time().unwrap_or(0);
+            i.follow_central_exp = central_exp > i.central_exp_time;
+            i.central_exp_time = central_exp;
+            i.update_claims(res.id_token(), &claims);
+            i.access_token = Some(Secret::new(res.access_token().secret().clone()));
+            i.id_token = res.id_token().map(|t| Secret::new(t.to_string()));
+            i.dpop_bound = i.dpop.is_some() && dpop::is_bound(res.token_type());
+            if let Some(t) = res.refresh_token() {
+                i.refresh_token = Some(Secret::new(t.secret().clone()));
+            }
+            i.refresh_failures = 0;
+            i.last_error = None;
+            i.save_session();
+            info!(exp, "tokens refreshed");
+
+            events.push((ZeroIDCEvent::RefreshSucceeded, json!({ "exp_time": exp })));
+            Some(i.refresh_due())
+        }
+        Err(RefreshFailure::Transient { reason, retry_after }) => {
+            i.refresh_failures += 1;
+            i.last_error = Some(SessionError::RefreshFailed(reason.clone()));
+            let now = SystemTime::now();
+            let exp_time = i.exp_time;
+            let exp = UNIX_EPOCH + Duration::from_secs(exp_time);
+            if now >= exp {
+                warn!("token refresh failed: {}. token has expired, giving up", reason);
+                end_session(&mut i);
+                events.push((
+                    ZeroIDCEvent::RefreshFailed,
+                    json!({ "reason": reason, "permanent": false }),
+                ));
+                events.push((ZeroIDCEvent::Expired, json!({ "exp_time": exp_time })));
+                events.push((ZeroIDCEvent::ReauthRequired, json!({ "reason": "session expired" })));
+                return None;
+            }
+
+            i.state = SessionState::Backoff;
+
+            // never sooner than the backoff, whatever Retry-After says, and one last
+            // try right at expiry if the backoff would take us past it
+            let delay = retry_after.unwrap_or_default().max(backoff_delay(i.refresh_failures));
+            let next = (now + delay).min(exp);
+            let retry_in = next.duration_since(now).unwrap_or(Duration::ZERO).as_secs();
+            warn!(
+                "token refresh failed: {}. retry {} in {}s",
+                reason, i.refresh_failures, retry_in
+            );
+            events.push((
+                ZeroIDCEvent::RefreshFailed,
+                json!({ "reason": reason, "permanent": false, "retry_in": retry_in }),
+            ));
+            if i.refresh_failures == 1 {
+                events.push((ZeroIDCEvent::AboutToExpire, json!({ "exp_time": exp_time })));
+            }
+            Some(next)
+        }
+        Err(RefreshFailure::Permanent(e)) => {
+            warn!("token refresh failed: {}. ending session", e);
+            let reason = e.to_string();
+            i.last_error = Some(e);
+            end_session(&mut i);
+            events.push((
+                ZeroIDCEvent::RefreshFailed,
+                json!({ "reason": reason, "permanent": true }),
+            ));
+            events.push((ZeroIDCEvent::ReauthRequired, json!({ "reason": reason })));
+            None
+        }
+    }
+}
+
+/// Exchanges the refresh token for new tokens and posts the new ID token to central.
+/// Returns the token response, the new ID token's verified claims and central's
+/// response.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn try_refresh(
+    inner: &Arc<Mutex<Inner>>,
+    client: &CoreClient,
+    client_auth: &ClientAuth,
+    dpop: Option<&DpopKey>,
+    refresh_token: &Secret,
+    nonce: Option<Secret>,
+    auth_endpoint: String,
+) -> Result<(CoreTokenResponse, CoreIdTokenClaims, CentralAuthResponse), RefreshFailure> {
+    // remember the status and Retry-After of the token endpoint's response, which
+    // the token error alone doesn't tell us
+    let last_response = Cell::new(None);
+    let (http, network_id) = {
+        let i = inner.lock().unwrap();
+        (i.http.clone(), i.network_id())
+    };
+    let assertion = client_auth
+        .assertion()
+        .map_err(|e| RefreshFailure::Permanent(SessionError::RefreshRejected(e.to_string())))?;
+    let refresh_token = RefreshToken::new(refresh_token.expose().to_string());
+    let mut req = client.exchange_refresh_token(&refresh_token);
+    if let Some(a) = assertion.as_ref() {
+        req = req
+            .add_extra_param("client_assertion_type", JWT_BEARER_ASSERTION)
+            .add_extra_param("client_assertion", a.expose());
+    }
+    let res = req
+        .request(
+            |req: HttpRequest| -> Result<HttpResponse, openidconnect::reqwest::Error<reqwest::Error>> {
+                let res = dpop::request(dpop, req, |req| http.request(req))?;
+                last_response.set(Some((res.status_code, retry_after(&res.headers))));
+                Ok(res)
+            },
+        )
+        .map_err(|e| RefreshFailure::from_token_error(e, last_response.get()))?;
+
+    // Refreshed ID tokens get the same checks as the one from the
+    // initial exchange.  They usually don't carry a nonce, but if
+    // one is present it has to be the original.
+    let nonce_check = |n: Option<&Nonce>| match (n, nonce.as_ref()) {
+        (None, _) => Ok(()),
+        (Some(got), Some(want)) if want.matches(got.secret()) => Ok(()),
+        _ => Err("nonce mismatch".to_string()),
+    };
+    // checked against a copy of the key cache, so refetching the keys doesn't keep the
+    // session locked
+    let mut jwks = inner.lock().unwrap().jwks.clone();
+    let verified = verify_token_response(&mut jwks, res, nonce_check);
+    inner.lock().unwrap().jwks.update_from(jwks);
+    let (res, claims) = verified.map_err(RefreshFailure::Permanent)?;
+    let policy = inner.lock().unwrap().policy.clone();
+    if let Some(t) = res.id_token() {
+        policy
+            .check(t, &claims)
+            .map_err(|v| RefreshFailure::Permanent(SessionError::PolicyRejected(v)))?;
+    }
+
+    let id_token = res
+        .id_token()
+        .map(|t| Secret::new(t.to_string()))
+        .ok_or(RefreshFailure::Permanent(SessionError::MissingIdToken))?;
+    let n = match nonce.as_ref() {
+        Some(n) => n.expose(),
+        None => "",
+    };
+
+    let params = [
+        ("id_token", id_token.expose()),
+        ("state", "refresh"),
+        ("extra_nonce", n),
+    ];
+    let r = http
+        .central()
+        .post(auth_endpoint)
+        .form(&params)
+        .send()
+        .map_err(|e| RefreshFailure::transient(format!("central post failed: {}", e)))?;
+
+    debug!("posted refreshed token to {}: {}", r.url().as_str(), r.status());
+
+    let status = r.status();
+    let headers = r.headers().clone();
+    let body = r
+        .bytes()
+        .map_err(|e| RefreshFailure::transient(format!("reading central response failed: {}", e)))?;
+    if !status.is_success() {
+        if let Ok(body) = std::str::from_utf8(&body) {
+            debug!("central response: {}", body);
+        }
+        return Err(RefreshFailure::from_central_status(
+            status,
+            &headers,
+            central::error_reason(&body),
+        ));
+    }
+    let central = CentralAuthResponse::parse(&body, network_id.as_deref()).map_err(|e| {
+        RefreshFailure::Permanent(SessionError::RefreshRejected(format!(
+            "invalid response from central: {}",
+            e
+        )))
+    })?;
+
+    Ok((res, claims, central))
+}
+
+/// Checks the ID token in a token response against the cached signing keys and the
+/// access token hash.  Returns the response along with the verified claims.
+///
+/// If the token is signed with a key we don't know, the JWKS is refetched once in case
+/// the IdP has rotated its keys.
+fn verify_token_response<N: NonceVerifier + Clone>(
+    jwks: &mut JwksCache,
+    res: CoreTokenResponse,
+    nonce_verifier: N,
+) -> Result<(CoreTokenResponse, CoreIdTokenClaims), SessionError> {
+    let id = match res.id_token() {
+        Some(t) => t,
+        None => return Err(SessionError::MissingIdToken),
+    };
+
+    jwks.refresh_if_stale();
+    let claims = match id.claims(&jwks.verifier(), nonce_verifier.clone()) {
+        Ok(claims) => claims.clone(),
+        Err(ClaimsVerificationError::SignatureVerification(SignatureVerificationError::NoMatchingKey))
+            if jwks.refresh_for_unknown_key() =>
+        {
+            id.claims(&jwks.verifier(), nonce_verifier)
+                .map_err(|e| SessionError::TokenVerification(e.to_string()))?
+                .clone()
+        }
+        Err(e) => return Err(SessionError::TokenVerification(e.to_string())),
+    };
+
+    let signing_algo = id
+        .signing_alg()
+        .map_err(|e| SessionError::TokenVerification(format!("no signing algorithm: {}", e)))?;
+
+    if let Some(expected_hash) = claims.access_token_hash() {
+        let actual_hash = AccessTokenHash::from_token(res.access_token(), &signing_algo)
+            .map_err(|e| SessionError::TokenVerification(format!("error hashing access token: {}", e)))?;
+
+        if actual_hash != *expected_hash {
+            return Err(SessionError::TokenVerification(
+                "access token hash mismatch".to_string(),
+            ));
+        }
+    }
+
+    Ok((res, claims))
+}
+
+/// Posts a verified ID token to central's auth endpoint and, if central accepts it,
+/// stores the tokens and expiry on `i`.  Returns central's response.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn post_token_to_central(
+    i: &mut Inner,
+    tok: &CoreTokenResponse,
+    claims: &CoreIdTokenClaims,
+) -> Result<CentralAuthResponse, SSOExchangeError> {
+    let id_token = match tok.id_token() {
+        Some(t) => t,
+        None => {
+            return Err(SessionError::MissingIdToken.into());
+        }
+    };
+    if let Err(v) = i.policy.check(id_token, claims) {
+        warn!("admission policy rejected the token: {}", v);
+        let e = SessionError::PolicyRejected(v);
+        i.last_error = Some(e.clone());
+        return Err(e.into());
+    }
+    let id_token = Secret::new(id_token.to_string());
+    let csrf_token = i.csrf_token.clone().unwrap_or_default();
+    let split = csrf_token.expose().split('_').collect::<Vec<&str>>();
+
+    if split.len() == 2 {
+        let params = [("id_token", id_token.expose()), ("state", split[0])];
+        let res = i
+            .http
+            .central()
+            .post(i.auth_endpoint.clone())
+            .form(&params)
+            .send()
+            .and_then(|res| {
+                let status = res.status();
+                res.bytes().map(|body| (status, body))
+            });
+
+        match res {
+            Ok((status, body)) => {
+                if status == 200 {
+                    debug!("posted token to {}: {}", i.auth_endpoint, status);
+
+                    let central = CentralAuthResponse::parse(&body, Some(split[1])).map_err(|e| {
+                        warn!("invalid response from central: {}", e);
+                        SSOExchangeError::InvalidCentralResponse(e)
+                    })?;
+
+                    // the claims were verified before we got here, and a token without an
+                    // expiration never gets that far
+                    i.exp_time = claims.expiration().timestamp().max(0) as u64;
+                    i.issued_time = claims.issue_time().timestamp().max(0) as u64;
+                    i.central_exp_time = central.exp_time().unwrap_or(0);
+                    i.follow_central_exp = true;
+                    i.subject = Some(claims.subject().to_string());
+                    i.update_claims(tok.id_token(), claims);
+                    info!(
+                        exp = i.exp_time,
+                        central_exp = i.central_exp_time,
+                        "central accepted the token"
+                    );
+
+                    i.access_token = Some(Secret::new(tok.access_token().secret().clone()));
+                    i.id_token = Some(id_token);
+                    i.dpop_bound = i.dpop.is_some() && dpop::is_bound(tok.token_type());
+                    if let Some(t) = tok.refresh_token() {
+                        i.refresh_token = Some(Secret::new(t.secret().clone()));
                     }
+                    i.save_session();
+
+                    Ok(central)
+                } else if status == 402 {
+                    Err(SSOExchangeError::LicenseRequired)
                 } else {
-                    i.running = false;
-                    Err(SSOExchangeError::new("invalid token response".to_string()))
+                    Err(SSOExchangeError::Central {
+                        status: status.as_u16(),
+                        reason: central::error_reason(&body),
+                    })
                 }
-            } else {
-                i.running = false;
-                Err(SSOExchangeError::new("invalid pkce verifier".to_string()))
             }
-        });
-        if should_start {
-            self.start();
-        }
-        match res {
-            Some(res) => res,
-            _ => Err(SSOExchangeError::new("invalid result".to_string())),
+            Err(res) => {
+                warn!("error posting token to {}: {}", i.auth_endpoint, res);
+                i.exp_time = 0;
+                Err(SSOExchangeError::CentralRequest(res.to_string()))
+            }
         }
+    } else {
+        Err(SSOExchangeError::InvalidState(
+            "error splitting state token".to_string(),
+        ))
     }
 }
@@ -0,0 +1,295 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! A loopback redirect listener (RFC 8252 section 7.3) for frontends that don't embed
+//! zerotier-one's web server.
+//!
+//! `start_loopback_login` binds a random port on 127.0.0.1, or on [::1] if there's no
+//! IPv4 loopback, and returns an authorization URL that redirects there.  A background
+//! thread waits for the browser to come back, checks `state`, exchanges the code and
+//! shows the user a page saying how it went.  The listener is closed once the login is
+//! over, when the session is stopped, or after [`LOGIN_TIMEOUT`].
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use openidconnect::RedirectUrl;
+use serde_json::json;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::error::{SSOExchangeError, ZeroIDCError};
+use crate::events::ZeroIDCEvent;
+use crate::{Inner, ZeroIDC};
+
+/// How long the user has to finish logging in.
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long a connection gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest request head we'll read.
+const MAX_REQUEST: usize = 16 * 1024;
+/// How often the listener checks whether the login was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+impl ZeroIDC {
+    /// Starts a login that redirects to a listener of our own rather than to
+    /// zerotier-one's `/sso` endpoint.  `set_nonce_and_csrf` must have been called
+    /// first.  Returns the URL to open in the user's browser.  The outcome is reported
+    /// with a `LoginSucceeded` or `LoginFailed` event.
+    pub fn start_loopback_login(&mut self) -> Result<String, SSOExchangeError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .or_else(|_| TcpListener::bind((Ipv6Addr::LOCALHOST, 0)))
+            .map_err(ZeroIDCError::from)?;
+        listener.set_nonblocking(true).map_err(ZeroIDCError::from)?;
+        let addr = listener.local_addr().map_err(ZeroIDCError::from)?;
+        let redirect = RedirectUrl::new(format!("http://{}/sso", addr)).map_err(ZeroIDCError::from)?;
+
+        {
+            let mut i = self.inner.lock().unwrap();
+            if i.state.is_running() || i.device_pending || i.loopback_pending {
+                return Err(SSOExchangeError::InvalidState(
+                    "authentication already in progress".to_string(),
+                ));
+            }
+            let (csrf, nonce) = match (i.csrf_token.clone(), i.nonce.clone()) {
+                (Some(csrf), Some(nonce)) => (csrf, nonce),
+                _ => return Err(SSOExchangeError::InvalidState("no state or nonce set".to_string())),
+            };
+
+            i.loopback_redirect = Some(redirect);
+            i.configure_client();
+            i.build_auth_url(csrf, nonce);
+            i.loopback_pending = true;
+        }
+
+        let url = self.auth_url();
+        if url.is_empty() {
+            end_loopback_login(&mut self.inner.lock().unwrap());
+            return Err(SSOExchangeError::InvalidState("no authorization URL".to_string()));
+        }
+
+        debug!("waiting for the login redirect on {}", addr);
+        let inner = Arc::clone(&self.inner);
+        let span = self.span();
+        spawn(move || span.in_scope(|| serve_login(inner, listener)));
+
+        Ok(url)
+    }
+}
+
+/// Puts the redirect URI and the authorization URL back the way they were.
+fn end_loopback_login(i: &mut Inner) {
+    i.loopback_pending = false;
+    i.loopback_redirect = None;
+    i.configure_client();
+    i.rebuild_auth_url();
+}
+
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn serve_login(inner: Arc<Mutex<Inner>>, listener: TcpListener) {
+    let deadline = Instant::now() + LOGIN_TIMEOUT;
+    let mut outcome = None;
+
+    while outcome.is_none() {
+        if !inner.lock().unwrap().loopback_pending {
+            debug!("loopback login cancelled");
+            break;
+        }
+        if Instant::now() >= deadline {
+            outcome = Some(Err("timed out waiting for the login to finish".to_string()));
+            break;
+        }
+
+        match listener.accept() {
+            Ok((mut stream, _)) => outcome = handle_connection(&inner, &mut stream),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => sleep(POLL_INTERVAL),
+            Err(e) => outcome = Some(Err(format!("error accepting connection: {}", e))),
+        }
+    }
+    drop(listener);
+
+    let sink = {
+        let mut i = inner.lock().unwrap();
+        // a failed login leaves the regular authorization URL ready for another try
+        end_loopback_login(&mut i);
+        i.events.clone()
+    };
+
+    let event = match outcome {
+        Some(Ok(exp_time)) => (ZeroIDCEvent::LoginSucceeded, json!({ "exp_time": exp_time })),
+        Some(Err(reason)) => {
+            warn!("loopback login failed: {}", reason);
+            (ZeroIDCEvent::LoginFailed, json!({ "reason": reason }))
+        }
+        None => return,
+    };
+    sink.deliver(vec![event]);
+}
+
+/// Answers one request to the listener.  Returns the login's outcome, the new expiry
+/// or why it failed, once the browser has come back from the IdP.
+#[cfg(any(
+    all(target_os = "linux", target_arch = "x86"),
+    all(target_os = "linux", target_arch = "x86_64"),
+    all(target_os = "linux", target_arch = "aarch64"),
+    target_os = "windows",
+    target_os = "macos",
+))]
+fn handle_connection(inner: &Arc<Mutex<Inner>>, stream: &mut TcpStream) -> Option<Result<u64, String>> {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+
+    let target = match read_request_target(stream) {
+        Some(t) => t,
+        None => {
+            respond(
+                stream,
+                "400 Bad Request",
+                "Login failed",
+                "The request could not be read.",
+            );
+            return None;
+        }
+    };
+    let url = match Url::parse("http://localhost").and_then(|base| base.join(&target)) {
+        Ok(url) if url.path() == "/sso" => url,
+        _ => {
+            respond(stream, "404 Not Found", "Not found", "");
+            return None;
+        }
+    };
+
+    let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.into_owned());
+    let expected = inner.lock().unwrap().csrf_token.clone();
+    match (param("state"), expected) {
+        (Some(state), Some(expected)) if expected.matches(&state) => {}
+        _ => {
+            // not the browser coming back from our authorization request, so keep waiting
+            respond(
+                stream,
+                "400 Bad Request",
+                "Login failed",
+                "The login request is invalid or out of date.",
+            );
+            return None;
+        }
+    }
+
+    if let Some(error) = param("error") {
+        let reason = match param("error_description") {
+            Some(d) => format!("identity provider returned {}: {}", error, d),
+            None => format!("identity provider returned {}", error),
+        };
+        respond(stream, "200 OK", "Login failed", &reason);
+        return Some(Err(reason));
+    }
+    let code = match param("code") {
+        Some(c) => c,
+        None => {
+            respond(
+                stream,
+                "400 Bad Request",
+                "Login failed",
+                "No authorization code was received.",
+            );
+            return Some(Err("no authorization code in redirect".to_string()));
+        }
+    };
+
+    let mut idc = ZeroIDC { inner: Arc::clone(inner) };
+    match idc.do_token_exchange(&code) {
+        Ok(_) => {
+            respond(
+                stream,
+                "200 OK",
+                "Login successful",
+                "You are logged in to the network.  You may close this window.",
+            );
+            Some(Ok(idc.get_exp_time()))
+        }
+        Err(e) => {
+            respond(stream, "200 OK", "Login failed", &e.to_string());
+            Some(Err(e.to_string()))
+        }
+    }
+}
+
+/// Reads a request head and returns the target of a GET request.
+fn read_request_target(stream: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST {
+            return None;
+        }
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => head.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let head = std::str::from_utf8(&head).ok()?;
+    let mut request_line = head.lines().next()?.split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => Some(target.to_string()),
+        _ => None,
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, title: &str, message: &str) {
+    let body = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\
+         <body><h1>{0}</h1><p>{1}</p></body></html>\n",
+        escape_html(title),
+        escape_html(message)
+    );
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.flush();
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
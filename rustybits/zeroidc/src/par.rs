@@ -0,0 +1,119 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Pushed Authorization Requests (RFC 9126).
+//!
+//! With PAR the authorization request is POSTed to the IdP's
+//! `pushed_authorization_request_endpoint`, authenticated like a token request, and the
+//! browser only gets a short URL with the `request_uri` the IdP handed back.  PAR is
+//! used when the IdP advertises the endpoint.  If it also requires PAR, or the provider
+//! profile does, a failed push leaves us without an authorization URL rather than
+//! falling back to a regular one.
+//!
+//! A `request_uri` is only good for a minute or so, so an expired one is replaced by
+//! pushing the same request again, with the same PKCE challenge, the next time the URL
+//! is asked for.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::client_auth::ClientAuth;
+use crate::error::PushedAuthorizationError;
+use crate::http::HttpClient;
+
+/// How long before its expiry a `request_uri` is replaced, so the user has time to open
+/// it.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// A pushed request and the regular authorization URL it stands in for.
+pub(crate) struct PushedRequest {
+    pub source: Url,
+    pub expires: Instant,
+}
+
+impl PushedRequest {
+    pub fn is_stale(&self) -> bool {
+        Instant::now() + EXPIRY_MARGIN >= self.expires
+    }
+}
+
+/// An authorization request that has to be pushed before its URL can be handed out,
+/// with everything needed to push it while the session isn't locked.
+pub(crate) struct PendingPush {
+    pub http: HttpClient,
+    pub endpoint: Option<Url>,
+    pub authorization_endpoint: Url,
+    pub client_auth: ClientAuth,
+    pub source: Url,
+}
+
+impl PendingPush {
+    pub fn push(&self) -> Result<(Url, Instant), PushedAuthorizationError> {
+        match self.endpoint.as_ref() {
+            Some(endpoint) => push(
+                &self.http,
+                endpoint,
+                &self.authorization_endpoint,
+                &self.client_auth,
+                &self.source,
+            ),
+            None => Err(PushedAuthorizationError::Request(
+                "identity provider has no pushed_authorization_request_endpoint".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PushResponse {
+    request_uri: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Pushes the parameters of the authorization URL `source` to `endpoint`.  Returns the
+/// URL to send the browser to and when it expires.
+pub(crate) fn push(
+    http: &HttpClient,
+    endpoint: &Url,
+    authorization_endpoint: &Url,
+    client_auth: &ClientAuth,
+    source: &Url,
+) -> Result<(Url, Instant), PushedAuthorizationError> {
+    let params = source.query_pairs().into_owned().collect();
+    let req = client_auth.form_request(endpoint.clone(), params)?;
+    let res = http
+        .request(req)
+        .map_err(|e| PushedAuthorizationError::Request(e.to_string()))?;
+
+    if !res.status_code.is_success() {
+        return Err(match serde_json::from_slice::<ErrorResponse>(&res.body) {
+            Ok(e) => PushedAuthorizationError::Provider { error: e.error, description: e.error_description },
+            Err(_) => PushedAuthorizationError::Request(format!("HTTP {}", res.status_code)),
+        });
+    }
+    let pushed: PushResponse = serde_json::from_slice(&res.body)
+        .map_err(|e| PushedAuthorizationError::Request(format!("invalid response: {}", e)))?;
+
+    let mut url = authorization_endpoint.clone();
+    url.query_pairs_mut()
+        .append_pair("client_id", &client_auth.client_id)
+        .append_pair("request_uri", &pushed.request_uri);
+    Ok((url, Instant::now() + Duration::from_secs(pushed.expires_in)))
+}
@@ -0,0 +1,139 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! A local admission policy, checked against every verified ID token before it's posted
+//! to central.
+//!
+//! Central makes the final decision either way.  The policy lets self-hosted and
+//! regulated deployments refuse a token on the node itself, e.g.
+//!
+//! ```json
+//! {
+//!     "required_groups": ["zerotier-users"],
+//!     "allowed_email_domains": ["example.com"],
+//!     "allowed_acr": ["urn:example:mfa"],
+//!     "required_amr": ["mfa"],
+//!     "max_auth_age": 43200
+//! }
+//! ```
+//!
+//! Every rule that's set has to pass.  `required_groups` needs any one of the groups in
+//! the `groups_claim` claim, `groups` by default.  Email domains only count if the IdP
+//! doesn't say the address is unverified.  `allowed_acr` and `max_auth_age` are also
+//! sent as `acr_values` and `max_age` in the authorization request, so the IdP knows to
+//! ask for them.  A refreshed token is checked again, so once `auth_time` is too old the
+//! user has to log in again.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openidconnect::core::{CoreIdToken, CoreIdTokenClaims};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::claims::extra_claims;
+use crate::error::PolicyViolation;
+
+const DEFAULT_GROUPS_CLAIM: &str = "groups";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct AdmissionPolicy {
+    pub required_groups: Vec<String>,
+    pub groups_claim: Option<String>,
+    pub allowed_email_domains: Vec<String>,
+    /// `acr` values, one of which the token has to carry.
+    pub allowed_acr: Vec<String>,
+    /// `amr` values the token has to carry all of.
+    pub required_amr: Vec<String>,
+    /// Longest time since the user last authenticated, in seconds.
+    pub max_auth_age: Option<u64>,
+}
+
+impl AdmissionPolicy {
+    pub fn from_json(json: &str) -> Result<AdmissionPolicy, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Authorization request parameters that help the user meet the policy.
+    pub fn auth_params(&self) -> Vec<(String, String)> {
+        let mut params = Vec::new();
+        if !self.allowed_acr.is_empty() {
+            params.push(("acr_values".to_string(), self.allowed_acr.join(" ")));
+        }
+        if let Some(age) = self.max_auth_age {
+            params.push(("max_age".to_string(), age.to_string()));
+        }
+        params
+    }
+
+    /// Checks a verified ID token and its claims.
+    pub fn check(&self, id_token: &CoreIdToken, claims: &CoreIdTokenClaims) -> Result<(), PolicyViolation> {
+        if !self.required_groups.is_empty() {
+            let claim = self.groups_claim.as_deref().unwrap_or(DEFAULT_GROUPS_CLAIM);
+            let groups = match extra_claims(id_token, &[claim.to_string()]).remove(claim) {
+                Some(Value::Array(groups)) => groups,
+                Some(group @ Value::String(_)) => vec![group],
+                _ => Vec::new(),
+            };
+            if !groups
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|g| self.required_groups.iter().any(|r| r == g))
+            {
+                return Err(PolicyViolation::MissingGroup);
+            }
+        }
+
+        if !self.allowed_email_domains.is_empty() {
+            if claims.email_verified() == Some(false) {
+                return Err(PolicyViolation::EmailNotVerified);
+            }
+            let domain = claims
+                .email()
+                .and_then(|e| e.as_str().rsplit_once('@'))
+                .map(|(_, domain)| domain.to_string());
+            match domain {
+                Some(d) if self.allowed_email_domains.iter().any(|a| a.eq_ignore_ascii_case(&d)) => {}
+                _ => return Err(PolicyViolation::EmailDomain(domain)),
+            }
+        }
+
+        if !self.allowed_acr.is_empty() {
+            let acr = claims.auth_context_ref().map(|a| a.as_str().to_string());
+            if !acr.as_ref().map(|a| self.allowed_acr.contains(a)).unwrap_or(false) {
+                return Err(PolicyViolation::Acr(acr));
+            }
+        }
+
+        let amr: Vec<&str> = match claims.auth_method_refs() {
+            Some(amr) => amr.iter().map(|a| a.as_str()).collect(),
+            None => Vec::new(),
+        };
+        if let Some(missing) = self.required_amr.iter().find(|r| !amr.contains(&r.as_str())) {
+            return Err(PolicyViolation::Amr(missing.clone()));
+        }
+
+        if let Some(max_age) = self.max_auth_age {
+            let auth_time = claims.auth_time().ok_or(PolicyViolation::MissingAuthTime)?.timestamp();
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64;
+            let age = now.saturating_sub(auth_time).max(0) as u64;
+            if age > max_age {
+                return Err(PolicyViolation::AuthTooOld(age));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Per-provider settings for the authorization request.
+//!
+//! Central tells us which provider a network uses by name.  The built-in profiles
+//! cover the providers we've always supported; anything else can be described in
+//! JSON and loaded through `zeroidc_load_provider_profiles`, e.g.
+//!
+//! ```json
+//! {
+//!     "authentik": {
+//!         "scopes": ["profile", "email"],
+//!         "offline_access": true
+//!     },
+//!     "zitadel": {
+//!         "scopes": ["profile", "email", "urn:zitadel:iam:org:project:id:zitadel:aud"],
+//!         "offline_access": true,
+//!         "extra_auth_params": { "prompt": "select_account" },
+//!         "claims": ["urn:zitadel:iam:org:project:roles"],
+//!         "token_endpoint_auth_method": "none"
+//!     }
+//! }
+//! ```
+//!
+//! Without a `token_endpoint_auth_method` the method is picked from the provider's
+//! discovery document and the credentials the client was given (see [`client_auth`]).
+//!
+//! [`client_auth`]: crate::client_auth
+
+use std::collections::{BTreeMap, HashMap};
+
+use openidconnect::{AuthType, Scope};
+use serde::{Deserialize, Serialize};
+
+/// How the client authenticates to the token endpoint, using the names from the
+/// `token_endpoint_auth_methods_supported` discovery field.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenEndpointAuthMethod {
+    /// Public client. `client_id` goes in the request body.
+    #[default]
+    None,
+    ClientSecretBasic,
+    ClientSecretPost,
+    /// A JWT signed with the client's private key (RFC 7523).
+    PrivateKeyJwt,
+}
+
+impl TokenEndpointAuthMethod {
+    pub fn auth_type(&self) -> AuthType {
+        match self {
+            TokenEndpointAuthMethod::ClientSecretBasic => AuthType::BasicAuth,
+            _ => AuthType::RequestBody,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ProviderProfile {
+    /// Scopes requested in addition to `openid`.
+    pub scopes: Vec<String>,
+    /// Whether `offline_access` must be requested to get a refresh token.
+    pub offline_access: bool,
+    /// Extra query parameters added to the authorization URL.
+    pub extra_auth_params: BTreeMap<String, String>,
+    /// Overrides the client authentication method picked from discovery.
+    pub token_endpoint_auth_method: Option<TokenEndpointAuthMethod>,
+    /// Never send a regular authorization URL, even if the IdP doesn't say it requires
+    /// pushed authorization requests.
+    pub require_pushed_authorization_requests: bool,
+    /// ID token claims besides the standard profile ones to keep, like `groups`.
+    pub claims: Vec<String>,
+}
+
+impl ProviderProfile {
+    fn builtin(scopes: &[&str], offline_access: bool) -> ProviderProfile {
+        ProviderProfile {
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            offline_access,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the built-in profile for `provider`.  Unknown providers get the
+    /// "default" profile.
+    pub fn for_provider(provider: &str) -> ProviderProfile {
+        match provider {
+            "auth0" => Self::builtin(&["profile", "email"], true),
+            "okta" => Self::builtin(&["profile", "email", "groups"], true),
+            "keycloak" => Self::builtin(&["profile", "email"], false),
+            "onelogin" => Self::builtin(&["profile", "email", "groups"], false),
+            _ => Self::builtin(&["profile", "email"], true),
+        }
+    }
+
+    /// The full list of scopes to request, not counting `openid`.
+    pub fn scopes(&self) -> Vec<Scope> {
+        let mut scopes: Vec<Scope> = self.scopes.iter().map(|s| Scope::new(s.clone())).collect();
+        if self.offline_access && !self.scopes.iter().any(|s| s == "offline_access") {
+            scopes.push(Scope::new("offline_access".to_string()));
+        }
+        scopes
+    }
+}
+
+/// Parses a JSON object mapping provider names to profiles.
+pub fn parse_profiles(json: &str) -> Result<HashMap<String, ProviderProfile>, serde_json::Error> {
+    serde_json::from_str(json)
+}
@@ -0,0 +1,137 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Deciding whether a failed token refresh is worth retrying, and when.
+//!
+//! An IdP or central being unreachable for a few seconds shouldn't kick anyone off a
+//! network.  Network errors, 5xx, 408 and 429 responses are retried with jittered
+//! exponential backoff until the ID token actually expires.  Anything that says the
+//! refresh token itself is no good, such as `invalid_grant`, ends the session.
+
+use std::time::{Duration, SystemTime};
+
+use openidconnect::core::CoreErrorResponseType;
+use openidconnect::http::header::{HeaderMap, RETRY_AFTER};
+use openidconnect::http::StatusCode;
+use openidconnect::{RequestTokenError, StandardErrorResponse};
+use rand::Rng;
+
+use crate::error::SessionError;
+
+/// Delay before the first retry.  Doubles with every further failure.
+const BASE_DELAY: Duration = Duration::from_secs(2);
+/// Upper bound on the delay between retries.
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) enum RefreshFailure {
+    /// Worth another try later.  Carries the server's `Retry-After`, if it sent one.
+    Transient {
+        reason: String,
+        retry_after: Option<Duration>,
+    },
+    /// The refresh token or the session is no good any more.
+    Permanent(SessionError),
+}
+
+impl RefreshFailure {
+    pub(crate) fn transient(reason: String) -> RefreshFailure {
+        RefreshFailure::Transient { reason, retry_after: None }
+    }
+
+    /// Classifies a failed token endpoint request.  `response` is the status and
+    /// `Retry-After` of the HTTP response, if one was received at all.
+    pub(crate) fn from_token_error<RE: std::error::Error + 'static>(
+        e: RequestTokenError<RE, StandardErrorResponse<CoreErrorResponseType>>,
+        response: Option<(StatusCode, Option<Duration>)>,
+    ) -> RefreshFailure {
+        let retry_after = response.and_then(|(_, r)| r);
+        let status_transient = match response {
+            Some((status, _)) => is_transient_status(status) || status.is_success(),
+            None => true,
+        };
+
+        match &e {
+            // couldn't reach the token endpoint at all
+            RequestTokenError::Request(_) => RefreshFailure::Transient { reason: e.to_string(), retry_after },
+            RequestTokenError::ServerResponse(r) => match r.error() {
+                CoreErrorResponseType::InvalidGrant
+                | CoreErrorResponseType::InvalidClient
+                | CoreErrorResponseType::InvalidRequest
+                | CoreErrorResponseType::InvalidScope
+                | CoreErrorResponseType::UnauthorizedClient
+                | CoreErrorResponseType::UnsupportedGrantType => {
+                    RefreshFailure::Permanent(SessionError::RefreshRejected(e.to_string()))
+                }
+                // temporarily_unavailable, server_error and friends
+                CoreErrorResponseType::Extension(_) => RefreshFailure::Transient { reason: e.to_string(), retry_after },
+            },
+            RequestTokenError::Parse(pe, body) => {
+                let malformed = match response {
+                    Some((status, _)) if status.is_success() => SessionError::from_parse_error(pe, body),
+                    _ => None,
+                };
+                match malformed {
+                    Some(se) => RefreshFailure::Permanent(se),
+                    // an error page from a load balancer or proxy, most likely
+                    None if status_transient => RefreshFailure::Transient { reason: e.to_string(), retry_after },
+                    None => RefreshFailure::Permanent(SessionError::RefreshRejected(e.to_string())),
+                }
+            }
+            RequestTokenError::Other(_) if status_transient => {
+                RefreshFailure::Transient { reason: e.to_string(), retry_after }
+            }
+            RequestTokenError::Other(_) => RefreshFailure::Permanent(SessionError::RefreshRejected(e.to_string())),
+        }
+    }
+
+    /// Classifies a non-2xx response from central.
+    pub(crate) fn from_central_status(
+        status: StatusCode,
+        headers: &HeaderMap,
+        reason: Option<String>,
+    ) -> RefreshFailure {
+        let reason = match reason {
+            Some(r) => format!("central returned {}: {}", status, r),
+            None => format!("central returned {}", status),
+        };
+        if is_transient_status(status) {
+            RefreshFailure::Transient { reason, retry_after: retry_after(headers) }
+        } else {
+            RefreshFailure::Permanent(SessionError::RefreshRejected(reason))
+        }
+    }
+}
+
+/// Whether a request that got `status` back might succeed if tried again later.
+pub(crate) fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Parses a `Retry-After` header, given either in seconds or as an HTTP date.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = httpdate::parse_http_date(value).ok()?;
+    Some(when.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+/// How long to wait after the `failures`th failure in a row.  Picks a random delay
+/// between half and all of the exponential backoff so nodes that lost their IdP at
+/// the same moment don't all come back at once.
+pub(crate) fn backoff_delay(failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(16);
+    let ceiling = BASE_DELAY.saturating_mul(1 << exp).min(MAX_DELAY);
+    let floor = ceiling / 2;
+    floor + ceiling.saturating_sub(floor).mul_f64(rand::thread_rng().gen::<f64>())
+}
@@ -0,0 +1,193 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! A single process-wide thread that schedules token refreshes for every SSO session.
+//!
+//! Sessions register the time their next refresh is due.  The thread sleeps until the
+//! earliest deadline and is woken early whenever a session is kicked, started or
+//! stopped, so hundreds of networks cost one mostly-idle thread instead of hundreds of
+//! threads polling once a second.  Each refresh runs on a short-lived thread of its
+//! own, so an IdP that's slow or unreachable only holds up its own sessions.  A session
+//! is never refreshed twice at once, since both would send the same refresh token; a
+//! kick during a refresh takes effect when it ends.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::thread::spawn;
+use std::time::{Duration, SystemTime};
+
+use crate::{refresh_session, Inner};
+
+/// Longest time the thread sleeps without looking at the clock, so deadlines are
+/// still met after the wall clock jumps (suspend/resume, NTP corrections).
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+static SCHEDULER: OnceLock<Arc<Scheduler<Mutex<Inner>>>> = OnceLock::new();
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Refreshes a session.  Returns when the next refresh is due, or `None` once the
+/// session has ended.
+pub(crate) type RefreshFn<T> = fn(&Arc<T>) -> Option<SystemTime>;
+
+struct Entry<T> {
+    session: Weak<T>,
+    /// While a refresh is in flight, the earliest deadline it was kicked with, if any.
+    due: Option<SystemTime>,
+    /// A refresh of the session is running.  No other one is started until it ends.
+    in_flight: bool,
+    /// Cancelled while a refresh was in flight.  Forgotten once it ends.
+    cancelled: bool,
+}
+
+pub(crate) struct Scheduler<T> {
+    sessions: Mutex<HashMap<u64, Entry<T>>>,
+    wake: Condvar,
+    refresh: RefreshFn<T>,
+}
+
+/// Returns a new id to register a session under.
+pub(crate) fn next_session_id() -> u64 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the process-wide scheduler, starting its thread on first use.
+pub(crate) fn scheduler() -> &'static Scheduler<Mutex<Inner>> {
+    SCHEDULER.get_or_init(|| Scheduler::start(refresh_session))
+}
+
+impl<T: Send + Sync + 'static> Scheduler<T> {
+    /// Creates a scheduler that refreshes sessions with `refresh`, and starts its thread.
+    pub(crate) fn start(refresh: RefreshFn<T>) -> Arc<Scheduler<T>> {
+        let s = Arc::new(Scheduler {
+            sessions: Mutex::new(HashMap::new()),
+            wake: Condvar::new(),
+            refresh,
+        });
+        let runner = Arc::clone(&s);
+        spawn(move || runner.run());
+        s
+    }
+
+    /// Schedules the next refresh of session `id` at `due`, replacing any earlier
+    /// deadline.  Never call this with the session's `Inner` locked.
+    pub(crate) fn schedule(&self, id: u64, session: &Arc<T>, due: SystemTime) {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(&id) {
+            // applied when the refresh in flight ends
+            Some(e) if e.in_flight => {
+                e.session = Arc::downgrade(session);
+                e.due = Some(e.due.map_or(due, |d| d.min(due)));
+                e.cancelled = false;
+            }
+            _ => {
+                sessions.insert(
+                    id,
+                    Entry {
+                        session: Arc::downgrade(session),
+                        due: Some(due),
+                        in_flight: false,
+                        cancelled: false,
+                    },
+                );
+            }
+        }
+        self.wake.notify_one();
+    }
+
+    /// Stops refreshing session `id`.
+    pub(crate) fn cancel(&self, id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(&id) {
+            Some(e) if e.in_flight => {
+                e.due = None;
+                e.cancelled = true;
+            }
+            _ => {
+                sessions.remove(&id);
+            }
+        }
+        self.wake.notify_one();
+    }
+
+    /// Whether session `id` is waiting for or in the middle of a refresh.
+    #[cfg(test)]
+    pub(crate) fn is_scheduled(&self, id: u64) -> bool {
+        self.sessions.lock().unwrap().get(&id).is_some_and(|e| !e.cancelled)
+    }
+
+    fn run(self: Arc<Self>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        loop {
+            let now = SystemTime::now();
+            let mut due = Vec::new();
+            sessions.retain(|id, e| match e.due {
+                Some(d) if d <= now && !e.in_flight => match e.session.upgrade() {
+                    Some(session) => {
+                        e.due = None;
+                        e.in_flight = true;
+                        due.push((*id, session));
+                        true
+                    }
+                    // the session is gone without having been stopped
+                    None => false,
+                },
+                _ => true,
+            });
+
+            for (id, session) in due {
+                let s = Arc::clone(&self);
+                spawn(move || {
+                    let next = (s.refresh)(&session);
+                    drop(session);
+                    s.finish(id, next);
+                });
+            }
+
+            let next = sessions.values().filter(|e| !e.in_flight).filter_map(|e| e.due).min();
+            let wait = match next {
+                Some(next) => next.duration_since(now).unwrap_or(Duration::ZERO).min(MAX_SLEEP),
+                None => MAX_SLEEP,
+            };
+            sessions = self.wake.wait_timeout(sessions, wait).unwrap().0;
+        }
+    }
+
+    /// Records the outcome of a refresh of session `id`.
+    fn finish(&self, id: u64, next: Option<SystemTime>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let ended = match sessions.get_mut(&id) {
+            Some(e) if e.cancelled => true,
+            Some(e) => match (e.due, next) {
+                // kicked while refreshing. keep the earlier deadline
+                (Some(kicked), Some(next)) => {
+                    e.due = Some(kicked.min(next));
+                    false
+                }
+                (Some(_), None) => false,
+                (None, Some(next)) => {
+                    e.due = Some(next);
+                    false
+                }
+                (None, None) => true,
+            },
+            None => false,
+        };
+        if let Some(e) = sessions.get_mut(&id) {
+            e.in_flight = false;
+        }
+        if ended {
+            sessions.remove(&id);
+        }
+        self.wake.notify_one();
+    }
+}
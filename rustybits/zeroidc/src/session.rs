@@ -0,0 +1,263 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Encrypted on-disk storage of SSO sessions so a restart of zerotier-one doesn't
+//! force every user to log in again.
+//!
+//! Sessions live in `<home>/sso.d/<network id>.session`, sealed with
+//! ChaCha20-Poly1305 under a random key kept in `<home>/sso.d/session.key`.  The key is
+//! shared by all networks and never replaced, since that would make every stored
+//! session unreadable.
+
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use zeroize::Zeroizing;
+
+use crate::claims::SessionClaims;
+use crate::secret::Secret;
+
+const SESSION_DIR: &str = "sso.d";
+const KEY_FILE: &str = "session.key";
+const KEY_LEN: usize = 32;
+const FILE_VERSION: u32 = 1;
+
+/// Token state saved between runs.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct StoredSession {
+    pub issuer: String,
+    pub client_id: String,
+    pub network_id: String,
+    pub refresh_token: Secret,
+    #[serde(default)]
+    pub access_token: Option<Secret>,
+    #[serde(default)]
+    pub nonce: Option<Secret>,
+    pub exp_time: u64,
+    /// When the ID token was issued, for refresh leads relative to its lifetime.
+    #[serde(default)]
+    pub issued_time: u64,
+    /// When central's authorization runs out, or 0 if central didn't say.
+    #[serde(default)]
+    pub central_exp_time: u64,
+    #[serde(default)]
+    pub claims: Option<SessionClaims>,
+    /// The DPoP key the tokens are bound to, if they are.
+    #[serde(default)]
+    pub dpop_key: Option<Secret>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SealedFile {
+    version: u32,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Where and under which identity a network's session is stored.
+pub struct SessionStore {
+    path: PathBuf,
+    key_path: PathBuf,
+    issuer: String,
+    client_id: String,
+    network_id: String,
+}
+
+impl SessionStore {
+    pub fn new(home: &Path, issuer: &str, client_id: &str, network_id: &str) -> SessionStore {
+        let dir = home.join(SESSION_DIR);
+        SessionStore {
+            path: dir.join(format!("{}.session", network_id)),
+            key_path: dir.join(KEY_FILE),
+            issuer: issuer.to_string(),
+            client_id: client_id.to_string(),
+            network_id: network_id.to_string(),
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn network_id(&self) -> &str {
+        &self.network_id
+    }
+
+    /// Loads the stored session.  A session that can't be decrypted or that belongs to
+    /// a different issuer, client or network is deleted and `None` is returned.  If the
+    /// key itself is unreadable, the session is left alone.
+    pub fn load(&self) -> Option<StoredSession> {
+        if !self.path.exists() {
+            return None;
+        }
+
+        let key = match self.key(false) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("can't read sso session key {}: {}", self.key_path.display(), e);
+                return None;
+            }
+        };
+        let session = match self.read(&key) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("discarding unreadable sso session {}: {}", self.path.display(), e);
+                self.clear();
+                return None;
+            }
+        };
+
+        if session.issuer != self.issuer || session.client_id != self.client_id || session.network_id != self.network_id
+        {
+            info!("sso configuration changed. discarding stored session");
+            self.clear();
+            return None;
+        }
+
+        Some(session)
+    }
+
+    pub fn save(&self, session: &StoredSession) {
+        if let Err(e) = self.write(session) {
+            warn!("error saving sso session {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn clear(&self) {
+        if self.path.exists() {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("error removing sso session {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    fn read(&self, key: &[u8]) -> Result<StoredSession, Box<dyn std::error::Error>> {
+        let sealed: SealedFile = serde_json::from_slice(&fs::read(&self.path)?)?;
+        if sealed.version != FILE_VERSION {
+            return Err(format!("unsupported session file version {}", sealed.version).into());
+        }
+
+        let nonce = BASE64.decode(sealed.nonce)?;
+        if nonce.len() != 12 {
+            return Err("invalid nonce".into());
+        }
+        let ciphertext = BASE64.decode(sealed.ciphertext)?;
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+                .map_err(|_| "decryption failed")?,
+        );
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    fn write(&self, session: &StoredSession) -> Result<(), Box<dyn std::error::Error>> {
+        let key = self.key(true)?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let plaintext = Zeroizing::new(serde_json::to_vec(session)?);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_ref())
+            .map_err(|_| "encryption failed")?;
+
+        let sealed = SealedFile {
+            version: FILE_VERSION,
+            nonce: BASE64.encode(nonce),
+            ciphertext: BASE64.encode(ciphertext),
+        };
+
+        write_private(&self.path, &serde_json::to_vec(&sealed)?)?;
+        Ok(())
+    }
+
+    /// Reads the session key, creating it first if `create` is set and there is none.
+    fn key(&self, create: bool) -> Result<Zeroizing<Vec<u8>>, Box<dyn std::error::Error>> {
+        match fs::read(&self.key_path).map(Zeroizing::new) {
+            Ok(key) if key.len() == KEY_LEN => return Ok(key),
+            Ok(key) => return Err(format!("invalid session key of {} bytes", key.len()).into()),
+            Err(e) if e.kind() == ErrorKind::NotFound && create => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        match create_private(&self.key_path, key.as_slice()) {
+            Ok(()) => Ok(Zeroizing::new(key.to_vec())),
+            // another network got there first, so use its key
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => self.key(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Writes `data` to `path` readable only by the owner, creating the parent directory
+/// if needed.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    let mut f = opts.open(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    drop(f);
+
+    fs::rename(&tmp, path)
+}
+
+/// Like `write_private`, but fails with `AlreadyExists` rather than replace an existing
+/// file.  The data is written to a temporary file first and then linked into place, so
+/// nobody ever reads a partial file.
+fn create_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let tmp = path.with_extension(format!("{:016x}.tmp", rand::thread_rng().gen::<u64>()));
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    let mut f = opts.open(&tmp)?;
+    let written = f.write_all(data).and_then(|_| f.sync_all());
+    drop(f);
+
+    // unlike a rename, linking doesn't replace a file someone else created meanwhile
+    let res = written.and_then(|_| fs::hard_link(&tmp, path));
+    let _ = fs::remove_file(&tmp);
+    res
+}
@@ -0,0 +1,77 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! The lifecycle of an SSO session.
+//!
+//! ```text
+//! Idle ─> AwaitingAuthorization ─> Exchanging ─> Active ─> Refreshing ─> Active
+//!                                      │                       │
+//!                                      └─> Idle (failed)       ├─> Backoff ─> Refreshing
+//!                                                              └─> Expired
+//! ```
+//!
+//! `stop` puts any session back to Idle, and `logout` ends it in LoggedOut.  Expired
+//! and LoggedOut sessions go back to AwaitingAuthorization once a new login starts.
+
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    /// Nothing has happened yet, or the session was stopped.
+    #[default]
+    Idle,
+    /// An authorization URL or device code is out and we're waiting for the user.
+    AwaitingAuthorization,
+    /// Trading an authorization code for tokens and posting them to central.
+    Exchanging,
+    /// Logged in.  The next refresh is scheduled.
+    Active,
+    /// A refresh is in progress.
+    Refreshing,
+    /// The last refresh failed for a reason that may go away.  It'll be retried until
+    /// the ID token expires.
+    Backoff,
+    /// The tokens couldn't be refreshed in time or were rejected.  The user has to log
+    /// in again.
+    Expired,
+    /// The session was ended with `logout`.
+    LoggedOut,
+}
+
+impl SessionState {
+    /// Whether the session is logged in and being kept fresh.
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self,
+            SessionState::Active | SessionState::Refreshing | SessionState::Backoff
+        )
+    }
+}
+
+/// A snapshot of a session for showing to users.  Times are seconds since the epoch.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionStatus {
+    pub state: SessionState,
+    pub last_error: Option<String>,
+    pub last_refresh: Option<u64>,
+    pub expires: Option<u64>,
+    /// When central's authorization of the member runs out, if central said.
+    pub central_expires: Option<u64>,
+    pub issuer: String,
+    pub subject: Option<String>,
+    /// Whether the session's tokens are bound to our DPoP key.
+    pub dpop: bool,
+    /// Seconds the IdP's clock is ahead of ours, going by the `Date` header of its last
+    /// response.  Negative if ours is ahead.
+    pub clock_offset: Option<i64>,
+}
@@ -0,0 +1,1748 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Drives logins and refreshes against a fake issuer that hands out broken tokens.
+//! None of these may panic. Each one has to end up as a session error instead.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openidconnect::core::{CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey};
+use openidconnect::http::{HeaderMap, HeaderValue};
+use openidconnect::{JsonWebKeyId, PrivateSigningKey};
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tiny_http::{Header, Response, Server};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::client_auth::{ClientKey, JWT_BEARER_ASSERTION};
+use crate::error::{HttpConfigError, PolicyViolation, SSOExchangeError, SessionError, ZeroIDCError};
+use crate::events::ZeroIDCEvent;
+use crate::http::{HttpClient, HttpConfig};
+use crate::jwks::cache_lifetime;
+use crate::policy::AdmissionPolicy;
+use crate::scheduler::Scheduler;
+use crate::secret::Secret;
+use crate::session::{SessionStore, StoredSession};
+use crate::state::SessionState;
+use crate::timing::{RefreshLead, TimingConfig};
+use crate::{refresh_session, ZeroIDC};
+
+const CLIENT_ID: &str = "zerotier-test";
+const KID: &str = "test-key";
+const NONCE: &str = "test-nonce";
+const STATE: &str = "test-state_8056c2e21c000001";
+
+/// What the fake issuer saw of a token request.
+#[derive(Clone)]
+struct TokenRequest {
+    authorization: Option<String>,
+    dpop: Option<String>,
+    user_agent: Option<String>,
+    params: HashMap<String, String>,
+}
+
+type TokenRequests = Arc<Mutex<Vec<TokenRequest>>>;
+
+/// Queued token endpoint responses, with their `Retry-After` header if any.
+type TokenResponses = Arc<Mutex<VecDeque<(u16, String, Option<String>)>>>;
+
+/// The form parameters of each pushed authorization request.
+type PushedRequests = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+/// An OpenID provider and central auth endpoint on a local port.  Token endpoint
+/// responses are queued up by the test.  Anything under `/moved` is redirected to the
+/// same path without it.
+struct FakeIssuer {
+    url: String,
+    key: CoreRsaPrivateSigningKey,
+    token_responses: TokenResponses,
+    token_requests: TokenRequests,
+    /// A nonce DPoP proofs have to carry, if any.
+    dpop_nonce: Arc<Mutex<Option<String>>>,
+    pushed_requests: PushedRequests,
+    /// The lifetime of the `request_uri`s handed out.
+    par_expires_in: Arc<Mutex<u64>>,
+    /// What central answers to posted tokens.
+    central_response: Arc<Mutex<(u16, String)>>,
+    /// The key set served at `/jwks` and its `Cache-Control` header.
+    jwks: Arc<Mutex<(String, Option<String>)>>,
+    jwks_fetches: Arc<Mutex<usize>>,
+    /// How long `/jwks` takes to answer.
+    jwks_delay: Arc<Mutex<Duration>>,
+    server: Arc<Server>,
+}
+
+impl FakeIssuer {
+    fn start() -> FakeIssuer {
+        FakeIssuer::start_with(|_| json!({}))
+    }
+
+    /// Starts an issuer with extra discovery metadata, made from its URL.
+    fn start_with(extra_discovery: impl Fn(&str) -> Value) -> FakeIssuer {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let url = format!("http://127.0.0.1:{}", server.server_addr().to_ip().unwrap().port());
+        let key = CoreRsaPrivateSigningKey::from_pem(
+            include_str!("../testdata/issuer-key.pem"),
+            Some(JsonWebKeyId::new(KID.to_string())),
+        )
+        .unwrap();
+        let token_responses = Arc::new(Mutex::new(VecDeque::new()));
+        let token_requests: TokenRequests = Arc::new(Mutex::new(Vec::new()));
+        let dpop_nonce: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let pushed_requests: PushedRequests = Arc::new(Mutex::new(Vec::new()));
+        let par_expires_in = Arc::new(Mutex::new(60u64));
+        let central_response = Arc::new(Mutex::new((200, "{}".to_string())));
+
+        let mut discovery = json!({
+            "issuer": url,
+            "authorization_endpoint": format!("{}/authorize", url),
+            "token_endpoint": format!("{}/token", url),
+            "jwks_uri": format!("{}/jwks", url),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "private_key_jwt"],
+            "dpop_signing_alg_values_supported": ["ES256"],
+        });
+        if let (Some(d), Value::Object(extra)) = (discovery.as_object_mut(), extra_discovery(&url)) {
+            d.extend(extra);
+        }
+        let discovery = discovery.to_string();
+        let jwks: Arc<Mutex<(String, Option<String>)>> = Arc::new(Mutex::new((key_set(&key, &[KID]), None)));
+        let jwks_fetches = Arc::new(Mutex::new(0));
+        let jwks_delay = Arc::new(Mutex::new(Duration::ZERO));
+        let device = json!({
+            "device_code": "device-code",
+            "user_code": "WDJB-MJHT",
+            "verification_uri": format!("{}/activate", url),
+            "expires_in": 600,
+            "interval": 0,
+        })
+        .to_string();
+
+        let srv = Arc::clone(&server);
+        let responses = Arc::clone(&token_responses);
+        let requests = Arc::clone(&token_requests);
+        let required_nonce = Arc::clone(&dpop_nonce);
+        let pushed = Arc::clone(&pushed_requests);
+        let expires_in = Arc::clone(&par_expires_in);
+        let central = Arc::clone(&central_response);
+        let key_set = Arc::clone(&jwks);
+        let fetches = Arc::clone(&jwks_fetches);
+        let delay = Arc::clone(&jwks_delay);
+        spawn(move || {
+            for mut req in srv.incoming_requests() {
+                let mut nonce_challenge = None;
+                if req.url() == "/token" {
+                    let header = |name: &'static str| {
+                        req.headers()
+                            .iter()
+                            .find(|h| h.field.equiv(name))
+                            .map(|h| h.value.to_string())
+                    };
+                    let (authorization, dpop, user_agent) =
+                        (header("Authorization"), header("DPoP"), header("User-Agent"));
+                    let mut body = String::new();
+                    let _ = req.as_reader().read_to_string(&mut body);
+                    if let Some(nonce) = required_nonce.lock().unwrap().clone() {
+                        if dpop.as_deref().map(|p| jwt_claims(p)["nonce"].clone()) != Some(json!(nonce)) {
+                            nonce_challenge = Some(nonce);
+                        }
+                    }
+                    requests.lock().unwrap().push(TokenRequest {
+                        authorization,
+                        dpop,
+                        user_agent,
+                        params: url::form_urlencoded::parse(body.as_bytes()).into_owned().collect(),
+                    });
+                }
+                if let Some(path) = req.url().strip_prefix("/moved") {
+                    let location = Header::from_bytes(&b"Location"[..], path.as_bytes()).unwrap();
+                    let _ = req.respond(Response::empty(307).with_header(location));
+                    continue;
+                }
+                if req.url() == "/par" {
+                    let mut body = String::new();
+                    let _ = req.as_reader().read_to_string(&mut body);
+                    let mut requests = pushed.lock().unwrap();
+                    requests.push(url::form_urlencoded::parse(body.as_bytes()).into_owned().collect());
+                    let res = json!({
+                        "request_uri": format!("urn:ietf:params:oauth:request_uri:{}", requests.len()),
+                        "expires_in": *expires_in.lock().unwrap(),
+                    });
+                    let _ = req.respond(Response::from_string(res.to_string()).with_status_code(201));
+                    continue;
+                }
+                let mut cache_control = None;
+                let mut retry_after: Option<String> = None;
+                let (status, body) = match (req.url(), nonce_challenge.as_ref()) {
+                    ("/.well-known/openid-configuration", _) => (200, discovery.clone()),
+                    ("/jwks", _) => {
+                        *fetches.lock().unwrap() += 1;
+                        std::thread::sleep(*delay.lock().unwrap());
+                        let (keys, cc) = key_set.lock().unwrap().clone();
+                        cache_control = cc;
+                        (200, keys)
+                    }
+                    ("/token", Some(_)) => (400, json!({ "error": "use_dpop_nonce" }).to_string()),
+                    ("/token", None) => {
+                        let (status, body, r) = responses.lock().unwrap().pop_front().unwrap_or((
+                            500,
+                            "no token response queued".to_string(),
+                            None,
+                        ));
+                        retry_after = r;
+                        (status, body)
+                    }
+                    ("/device", _) => (200, device.clone()),
+                    ("/central", _) => central.lock().unwrap().clone(),
+                    _ => (404, "".to_string()),
+                };
+                let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+                let mut response = Response::from_string(body)
+                    .with_status_code(status)
+                    .with_header(content_type);
+                if let Some(nonce) = nonce_challenge {
+                    response.add_header(Header::from_bytes(&b"DPoP-Nonce"[..], nonce.as_bytes()).unwrap());
+                }
+                if let Some(cc) = cache_control {
+                    response.add_header(Header::from_bytes(&b"Cache-Control"[..], cc.as_bytes()).unwrap());
+                }
+                if let Some(r) = retry_after {
+                    response.add_header(Header::from_bytes(&b"Retry-After"[..], r.as_bytes()).unwrap());
+                }
+                let _ = req.respond(response);
+            }
+        });
+
+        FakeIssuer {
+            url,
+            key,
+            token_responses,
+            token_requests,
+            dpop_nonce,
+            pushed_requests,
+            par_expires_in,
+            central_response,
+            jwks,
+            jwks_fetches,
+            jwks_delay,
+            server,
+        }
+    }
+
+    fn last_token_request(&self) -> TokenRequest {
+        self.token_requests.lock().unwrap().last().cloned().unwrap()
+    }
+
+    fn queue_token_response(&self, status: u16, body: Value) {
+        self.token_responses
+            .lock()
+            .unwrap()
+            .push_back((status, body.to_string(), None));
+    }
+
+    fn queue_retry_after(&self, status: u16, body: Value, retry_after: &str) {
+        self.token_responses
+            .lock()
+            .unwrap()
+            .push_back((status, body.to_string(), Some(retry_after.to_string())));
+    }
+
+    /// Publishes the issuer's key under each of `kids`.
+    fn publish_keys(&self, kids: &[&str], cache_control: Option<&str>) {
+        *self.jwks.lock().unwrap() = (key_set(&self.key, kids), cache_control.map(|c| c.to_string()));
+    }
+
+    fn jwks_fetches(&self) -> usize {
+        *self.jwks_fetches.lock().unwrap()
+    }
+
+    fn set_central_response(&self, status: u16, body: Value) {
+        *self.central_response.lock().unwrap() = (status, body.to_string());
+    }
+
+    /// Claims for a token that's good for `lifetime` seconds.
+    fn claims(&self, nonce: Option<&str>, lifetime: i64) -> Value {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let mut claims = json!({
+            "iss": self.url,
+            "aud": CLIENT_ID,
+            "sub": "user",
+            "iat": now,
+            "exp": now + lifetime,
+        });
+        if let Some(nonce) = nonce {
+            claims["nonce"] = json!(nonce);
+        }
+        claims
+    }
+
+    fn sign(&self, claims: &Value) -> String {
+        self.sign_with_kid(KID, claims)
+    }
+
+    /// Signs with the issuer's key, but names it `kid`.
+    fn sign_with_kid(&self, kid: &str, claims: &Value) -> String {
+        let header = json!({ "alg": "RS256", "typ": "JWT", "kid": kid });
+        let input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let sig = self
+            .key
+            .sign(&CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256, input.as_bytes())
+            .unwrap();
+        format!("{}.{}", input, URL_SAFE_NO_PAD.encode(sig))
+    }
+
+    fn valid_id_token(&self, nonce: Option<&str>) -> String {
+        self.sign(&self.claims(nonce, 3600))
+    }
+
+    /// Creates a client for the issuer, ready to exchange an authorization code.
+    fn client(&self) -> ZeroIDC {
+        let mut idc = ZeroIDC::new(&self.url, CLIENT_ID, "default", &format!("{}/central", self.url), 9993).unwrap();
+        idc.set_nonce_and_csrf(STATE.to_string(), NONCE.to_string());
+        idc
+    }
+
+    /// Creates a client with a logged in session.
+    fn logged_in(&self) -> ZeroIDC {
+        let mut idc = self.client();
+        self.queue_token_response(200, token_response(Some(self.valid_id_token(Some(NONCE)))));
+        idc.do_token_exchange("code").unwrap();
+        assert!(idc.is_running());
+        idc
+    }
+}
+
+impl Drop for FakeIssuer {
+    fn drop(&mut self) {
+        self.server.unblock();
+    }
+}
+
+/// A JWKS with `key` under each of `kids`.
+fn key_set(key: &CoreRsaPrivateSigningKey, kids: &[&str]) -> String {
+    let keys: Vec<Value> = kids
+        .iter()
+        .map(|kid| {
+            let mut jwk = serde_json::to_value(key.as_verification_key()).unwrap();
+            jwk["kid"] = json!(kid);
+            jwk
+        })
+        .collect();
+    json!({ "keys": keys }).to_string()
+}
+
+/// The decoded header or claims of a JWT.
+fn jwt_part(jwt: &str, part: usize) -> Value {
+    let part = jwt.split('.').nth(part).unwrap();
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+}
+
+fn jwt_claims(jwt: &str) -> Value {
+    jwt_part(jwt, 1)
+}
+
+fn token_response(id_token: Option<String>) -> Value {
+    let mut res = json!({
+        "access_token": "access",
+        "token_type": "Bearer",
+        "expires_in": 3600,
+        "refresh_token": "refresh",
+    });
+    if let Some(id_token) = id_token {
+        res["id_token"] = json!(id_token);
+    }
+    res
+}
+
+/// Header and signature of a real token around arbitrary claims.
+fn with_claims(issuer: &FakeIssuer, claims: Value) -> String {
+    let token = issuer.valid_id_token(Some(NONCE));
+    let parts: Vec<&str> = token.split('.').collect();
+    format!(
+        "{}.{}.{}",
+        parts[0],
+        URL_SAFE_NO_PAD.encode(claims.to_string()),
+        parts[2]
+    )
+}
+
+fn exchange_error(issuer: &FakeIssuer, id_token: Option<String>) -> (ZeroIDC, SSOExchangeError) {
+    let mut idc = issuer.client();
+    issuer.queue_token_response(200, token_response(id_token));
+    let err = idc.do_token_exchange("code").unwrap_err();
+    assert!(!idc.is_running());
+    (idc, err)
+}
+
+fn refresh_error(issuer: &FakeIssuer, status: u16, body: Value) -> (ZeroIDC, Option<SessionError>) {
+    let mut idc = issuer.logged_in();
+    issuer.queue_token_response(status, body);
+    assert_eq!(refresh_session(&idc.inner), None);
+    assert!(!idc.is_running());
+    assert_eq!(idc.get_exp_time(), 0);
+    let err = idc.inner.lock().unwrap().last_error.clone();
+    (idc, err)
+}
+
+#[test]
+fn exchange_with_valid_token() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    assert!(idc.get_exp_time() > 0);
+    assert!(idc.last_error().is_none());
+    idc.stop();
+}
+
+#[test]
+fn exchange_without_id_token() {
+    let issuer = FakeIssuer::start();
+    let (idc, err) = exchange_error(&issuer, None);
+    assert!(matches!(err, SSOExchangeError::Session(SessionError::MissingIdToken)));
+    assert!(idc.last_error().is_some());
+}
+
+#[test]
+fn exchange_with_garbage_id_token() {
+    let issuer = FakeIssuer::start();
+    let (idc, err) = exchange_error(&issuer, Some("not a jwt".to_string()));
+    assert!(matches!(
+        err,
+        SSOExchangeError::Session(SessionError::MalformedTokenResponse(_))
+    ));
+    assert!(idc.last_error().is_some());
+}
+
+#[test]
+fn exchange_with_id_token_without_expiration() {
+    let issuer = FakeIssuer::start();
+    let mut claims = issuer.claims(Some(NONCE), 3600);
+    claims.as_object_mut().unwrap().remove("exp");
+    let (_, err) = exchange_error(&issuer, Some(issuer.sign(&claims)));
+    assert!(matches!(
+        err,
+        SSOExchangeError::Session(SessionError::MalformedTokenResponse(_))
+    ));
+}
+
+#[test]
+fn exchange_with_unparsable_claims() {
+    let issuer = FakeIssuer::start();
+    let token = with_claims(&issuer, json!("not an object"));
+    let (_, err) = exchange_error(&issuer, Some(token));
+    assert!(matches!(
+        err,
+        SSOExchangeError::Session(SessionError::MalformedTokenResponse(_))
+    ));
+}
+
+#[test]
+fn exchange_with_bad_signature() {
+    let issuer = FakeIssuer::start();
+    let mut claims = issuer.claims(Some(NONCE), 3600);
+    let token = issuer.sign(&claims);
+    claims["sub"] = json!("someone-else");
+    let forged = with_claims(&issuer, claims);
+    assert_ne!(token, forged);
+    let (_, err) = exchange_error(&issuer, Some(forged));
+    assert!(matches!(
+        err,
+        SSOExchangeError::Session(SessionError::TokenVerification(_))
+    ));
+}
+
+#[test]
+fn exchange_with_expired_token() {
+    let issuer = FakeIssuer::start();
+    let token = issuer.sign(&issuer.claims(Some(NONCE), -3600));
+    let (_, err) = exchange_error(&issuer, Some(token));
+    assert!(matches!(
+        err,
+        SSOExchangeError::Session(SessionError::TokenVerification(_))
+    ));
+}
+
+#[test]
+fn exchange_with_wrong_nonce() {
+    let issuer = FakeIssuer::start();
+    let token = issuer.valid_id_token(Some("some-other-nonce"));
+    let (_, err) = exchange_error(&issuer, Some(token));
+    assert!(matches!(
+        err,
+        SSOExchangeError::Session(SessionError::TokenVerification(_))
+    ));
+}
+
+#[test]
+fn refresh_with_valid_token() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert!(refresh_session(&idc.inner).is_some());
+    assert!(idc.is_running());
+    assert!(idc.last_error().is_none());
+    idc.stop();
+}
+
+#[test]
+fn refresh_without_id_token() {
+    let issuer = FakeIssuer::start();
+    let (_, err) = refresh_error(&issuer, 200, token_response(None));
+    assert!(matches!(err, Some(SessionError::MissingIdToken)));
+}
+
+#[test]
+fn refresh_with_id_token_without_expiration() {
+    let issuer = FakeIssuer::start();
+    let mut claims = issuer.claims(None, 3600);
+    claims.as_object_mut().unwrap().remove("exp");
+    let (_, err) = refresh_error(&issuer, 200, token_response(Some(issuer.sign(&claims))));
+    assert!(matches!(err, Some(SessionError::MalformedTokenResponse(_))));
+}
+
+#[test]
+fn refresh_with_garbage_id_token() {
+    let issuer = FakeIssuer::start();
+    let (_, err) = refresh_error(&issuer, 200, token_response(Some("a.b.c".to_string())));
+    assert!(matches!(err, Some(SessionError::MalformedTokenResponse(_))));
+}
+
+#[test]
+fn refresh_with_wrong_nonce() {
+    let issuer = FakeIssuer::start();
+    let token = issuer.valid_id_token(Some("some-other-nonce"));
+    let (_, err) = refresh_error(&issuer, 200, token_response(Some(token)));
+    assert!(matches!(err, Some(SessionError::TokenVerification(_))));
+}
+
+#[test]
+fn refresh_with_invalid_grant() {
+    let issuer = FakeIssuer::start();
+    let (_, err) = refresh_error(&issuer, 400, json!({ "error": "invalid_grant" }));
+    assert!(matches!(err, Some(SessionError::RefreshRejected(_))));
+}
+
+/// Refreshes a session that's been told to back off, and returns how long until the
+/// next try.
+fn backoff(idc: &ZeroIDC) -> Duration {
+    let now = SystemTime::now();
+    let next = refresh_session(&idc.inner).unwrap();
+    assert_eq!(idc.state(), SessionState::Backoff);
+    assert!(matches!(
+        idc.inner.lock().unwrap().last_error,
+        Some(SessionError::RefreshFailed(_))
+    ));
+    next.duration_since(now).unwrap_or(Duration::ZERO)
+}
+
+#[test]
+fn refresh_retry_after() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    let unavailable = json!({ "error": "temporarily_unavailable" });
+
+    issuer.queue_retry_after(503, unavailable.clone(), "120");
+    let delay = backoff(&idc);
+    assert!(
+        delay > Duration::from_secs(110) && delay <= Duration::from_secs(121),
+        "{:?}",
+        delay
+    );
+
+    // a Retry-After that's already up doesn't mean right away
+    issuer.queue_retry_after(503, unavailable.clone(), "0");
+    assert!(backoff(&idc) >= Duration::from_secs(1));
+    issuer.queue_retry_after(503, unavailable, "Wed, 21 Oct 2015 07:28:00 GMT");
+    assert!(backoff(&idc) >= Duration::from_secs(2));
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert!(refresh_session(&idc.inner).is_some());
+    assert!(idc.last_error().is_none());
+    idc.stop();
+}
+
+#[test]
+fn refresh_connection_refused() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    idc.inner.lock().unwrap().auth_endpoint = format!("http://127.0.0.1:{}/central", port);
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert!(backoff(&idc) >= Duration::from_secs(1));
+    assert!(idc.is_running());
+    assert!(idc.last_error().unwrap().contains("central post failed"));
+    idc.stop();
+}
+
+/// Records the events delivered to `idc`.
+fn record_events(idc: &mut ZeroIDC) -> Arc<Mutex<Vec<ZeroIDCEvent>>> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&events);
+    idc.set_event_handler(Some(Arc::new(move |event, _: &Value| {
+        recorded.lock().unwrap().push(event);
+    })));
+    events
+}
+
+#[test]
+fn refresh_events() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    let events = record_events(&mut idc);
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert!(refresh_session(&idc.inner).is_some());
+    issuer.queue_token_response(503, json!({ "error": "temporarily_unavailable" }));
+    assert!(refresh_session(&idc.inner).is_some());
+    issuer.queue_token_response(400, json!({ "error": "invalid_grant" }));
+    assert!(refresh_session(&idc.inner).is_none());
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            ZeroIDCEvent::RefreshSucceeded,
+            ZeroIDCEvent::RefreshFailed,
+            ZeroIDCEvent::AboutToExpire,
+            ZeroIDCEvent::RefreshFailed,
+            ZeroIDCEvent::ReauthRequired,
+        ]
+    );
+}
+
+#[test]
+fn session_states() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    assert_eq!(idc.state(), SessionState::AwaitingAuthorization);
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    idc.do_token_exchange("code").unwrap();
+    assert_eq!(idc.state(), SessionState::Active);
+    let status = idc.status();
+    assert_eq!(status.subject.as_deref(), Some("user"));
+    assert_eq!(status.issuer, issuer.url);
+    assert!(status.expires.is_some());
+
+    issuer.queue_token_response(503, json!({ "error": "temporarily_unavailable" }));
+    refresh_session(&idc.inner);
+    assert_eq!(idc.state(), SessionState::Backoff);
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    refresh_session(&idc.inner);
+    assert_eq!(idc.state(), SessionState::Active);
+    assert!(idc.status().last_refresh.is_some());
+
+    issuer.queue_token_response(400, json!({ "error": "invalid_grant" }));
+    refresh_session(&idc.inner);
+    assert_eq!(idc.state(), SessionState::Expired);
+    assert!(idc.status().last_error.is_some());
+
+    idc.logout();
+    assert_eq!(idc.state(), SessionState::LoggedOut);
+}
+
+#[test]
+fn device_flow() {
+    let issuer = FakeIssuer::start_with(|url| json!({ "device_authorization_endpoint": format!("{}/device", url) }));
+
+    // central needs the state to accept the token
+    let mut idc = ZeroIDC::new(
+        &issuer.url,
+        CLIENT_ID,
+        "default",
+        &format!("{}/central", issuer.url),
+        9993,
+    )
+    .unwrap();
+    assert!(matches!(
+        idc.start_device_flow(),
+        Err(SSOExchangeError::InvalidState(_))
+    ));
+
+    let mut idc = issuer.client();
+    let events = record_events(&mut idc);
+    issuer.queue_token_response(400, json!({ "error": "authorization_pending" }));
+    issuer.queue_token_response(400, json!({ "error": "slow_down" }));
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+
+    let auth = idc.start_device_flow().unwrap();
+    assert_eq!(auth.user_code, "WDJB-MJHT");
+    assert_eq!(auth.verification_uri, format!("{}/activate", issuer.url));
+    assert_eq!(wait_for_event(&events), ZeroIDCEvent::LoginSucceeded);
+    assert!(idc.is_running());
+    assert!(idc.get_exp_time() > 0);
+
+    let requests = issuer.token_requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 3);
+    for req in requests {
+        assert_eq!(req.params["grant_type"], "urn:ietf:params:oauth:grant-type:device_code");
+        assert_eq!(req.params["device_code"], "device-code");
+    }
+    idc.stop();
+}
+
+/// Starts a loopback login and returns the redirect URI it's listening on.
+fn start_loopback(idc: &mut ZeroIDC) -> String {
+    let url = url::Url::parse(&idc.start_loopback_login().unwrap()).unwrap();
+    let redirect = url.query_pairs().find(|(k, _)| k == "redirect_uri").unwrap().1;
+    assert!(redirect.starts_with("http://127.0.0.1:") || redirect.starts_with("http://[::1]:"));
+    redirect.into_owned()
+}
+
+fn wait_for_event(events: &Arc<Mutex<Vec<ZeroIDCEvent>>>) -> ZeroIDCEvent {
+    for _ in 0..200 {
+        if let Some(event) = events.lock().unwrap().first() {
+            return *event;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    panic!("no event");
+}
+
+#[test]
+fn loopback_login() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    let events = record_events(&mut idc);
+    let redirect = start_loopback(&mut idc);
+
+    // stray requests don't end the login
+    let res = reqwest::blocking::get(format!("{}?state=wrong&code=code", redirect)).unwrap();
+    assert_eq!(res.status(), 400);
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    let res = reqwest::blocking::get(format!("{}?state={}&code=code", redirect, STATE)).unwrap();
+    assert_eq!(res.status(), 200);
+    assert!(res.text().unwrap().contains("Login successful"));
+
+    assert_eq!(wait_for_event(&events), ZeroIDCEvent::LoginSucceeded);
+    assert_eq!(idc.state(), SessionState::Active);
+    assert!(reqwest::blocking::get(&redirect).is_err());
+    idc.stop();
+}
+
+#[test]
+fn loopback_login_denied() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    let events = record_events(&mut idc);
+    let redirect = start_loopback(&mut idc);
+
+    let res = reqwest::blocking::get(format!("{}?state={}&error=access_denied", redirect, STATE)).unwrap();
+    assert!(res.text().unwrap().contains("access_denied"));
+
+    assert_eq!(wait_for_event(&events), ZeroIDCEvent::LoginFailed);
+    assert_eq!(idc.state(), SessionState::AwaitingAuthorization);
+    let url = url::Url::parse(&idc.auth_url()).unwrap();
+    let redirect = url.query_pairs().find(|(k, _)| k == "redirect_uri").unwrap().1;
+    assert_eq!(redirect, "http://localhost:9993/sso");
+}
+
+#[test]
+fn public_client_sends_client_id() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    let req = issuer.last_token_request();
+    assert_eq!(req.authorization, None);
+    assert_eq!(req.params.get("client_id").map(String::as_str), Some(CLIENT_ID));
+    assert!(!req.params.contains_key("client_secret"));
+    assert_eq!(req.dpop, None);
+    assert!(req.user_agent.unwrap().starts_with("zeroidc/"));
+    idc.stop();
+}
+
+#[test]
+fn client_secret_basic() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    idc.set_client_secret(Some("s3cret"));
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    idc.do_token_exchange("code").unwrap();
+
+    let req = issuer.last_token_request();
+    let expected = base64::engine::general_purpose::STANDARD.encode(format!("{}:s3cret", CLIENT_ID));
+    assert_eq!(req.authorization, Some(format!("Basic {}", expected)));
+    assert!(!req.params.contains_key("client_secret"));
+    idc.stop();
+}
+
+#[test]
+fn client_secret_post_from_profile() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    idc.set_client_secret(Some("s3cret"));
+    let profiles = json!({ "default": { "token_endpoint_auth_method": "client_secret_post" } });
+    assert!(idc.load_provider_profiles(&profiles.to_string()).unwrap());
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    idc.do_token_exchange("code").unwrap();
+
+    let req = issuer.last_token_request();
+    assert_eq!(req.authorization, None);
+    assert_eq!(req.params.get("client_id").map(String::as_str), Some(CLIENT_ID));
+    assert_eq!(req.params.get("client_secret").map(String::as_str), Some("s3cret"));
+    idc.stop();
+}
+
+#[test]
+fn private_key_jwt() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    idc.set_client_secret(Some("s3cret"));
+    let key = ClientKey::from_pem(include_str!("../testdata/issuer-key.pem"), Some("client-key")).unwrap();
+    idc.set_client_key(Some(key));
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    idc.do_token_exchange("code").unwrap();
+
+    // the key wins over the secret
+    let req = issuer.last_token_request();
+    assert_eq!(req.authorization, None);
+    assert!(!req.params.contains_key("client_secret"));
+    assert_eq!(
+        req.params.get("client_assertion_type").map(String::as_str),
+        Some(JWT_BEARER_ASSERTION)
+    );
+
+    let assertion = req.params.get("client_assertion").unwrap();
+    assert_eq!(assertion.split('.').count(), 3);
+    let header = jwt_part(assertion, 0);
+    assert_eq!(header["alg"], "RS256");
+    assert_eq!(header["kid"], "client-key");
+    let claims = jwt_claims(assertion);
+    assert_eq!(claims["iss"], CLIENT_ID);
+    assert_eq!(claims["sub"], CLIENT_ID);
+    assert_eq!(claims["aud"], format!("{}/token", issuer.url));
+    assert!(claims["exp"].as_u64().unwrap() > claims["iat"].as_u64().unwrap());
+
+    // refreshes get a new assertion
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert!(refresh_session(&idc.inner).is_some());
+    let refresh = issuer.last_token_request();
+    assert_eq!(
+        refresh.params.get("grant_type").map(String::as_str),
+        Some("refresh_token")
+    );
+    assert_ne!(refresh.params.get("client_assertion"), Some(assertion));
+    idc.stop();
+}
+
+/// A token response for tokens bound to a DPoP key.
+fn dpop_token_response(id_token: String) -> Value {
+    let mut res = token_response(Some(id_token));
+    res["token_type"] = json!("DPoP");
+    res
+}
+
+#[test]
+fn dpop_bound_session() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    assert!(idc.set_dpop(true));
+    let url = url::Url::parse(&idc.auth_url()).unwrap();
+    let jkt = url
+        .query_pairs()
+        .find(|(k, _)| k == "dpop_jkt")
+        .map(|(_, v)| v.into_owned());
+    assert!(jkt.is_some());
+
+    issuer.queue_token_response(200, dpop_token_response(issuer.valid_id_token(Some(NONCE))));
+    idc.do_token_exchange("code").unwrap();
+    assert!(idc.status().dpop);
+
+    let proof = issuer.last_token_request().dpop.unwrap();
+    let header = jwt_part(&proof, 0);
+    assert_eq!(header["typ"], "dpop+jwt");
+    assert_eq!(header["alg"], "ES256");
+    assert_eq!(header["jwk"]["kty"], "EC");
+    let claims = jwt_claims(&proof);
+    assert_eq!(claims["htm"], "POST");
+    assert_eq!(claims["htu"], format!("{}/token", issuer.url));
+
+    // refreshes are signed with the same key
+    issuer.queue_token_response(200, dpop_token_response(issuer.valid_id_token(None)));
+    assert!(refresh_session(&idc.inner).is_some());
+    let refresh_proof = issuer.last_token_request().dpop.unwrap();
+    assert_eq!(jwt_part(&refresh_proof, 0)["jwk"], header["jwk"]);
+    assert_ne!(jwt_claims(&refresh_proof)["jti"], claims["jti"]);
+
+    // a new session gets a new key
+    idc.logout();
+    assert!(!idc.status().dpop);
+    idc.set_nonce_and_csrf(STATE.to_string(), NONCE.to_string());
+    let url = url::Url::parse(&idc.auth_url()).unwrap();
+    let new_jkt = url
+        .query_pairs()
+        .find(|(k, _)| k == "dpop_jkt")
+        .map(|(_, v)| v.into_owned());
+    assert!(new_jkt.is_some());
+    assert_ne!(new_jkt, jkt);
+}
+
+#[test]
+fn dpop_nonce_challenge() {
+    let issuer = FakeIssuer::start();
+    *issuer.dpop_nonce.lock().unwrap() = Some("server-nonce".to_string());
+    let mut idc = issuer.client();
+    assert!(idc.set_dpop(true));
+
+    issuer.queue_token_response(200, dpop_token_response(issuer.valid_id_token(Some(NONCE))));
+    idc.do_token_exchange("code").unwrap();
+
+    let requests = issuer.token_requests.lock().unwrap().clone();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(jwt_claims(requests[0].dpop.as_ref().unwrap())["nonce"], Value::Null);
+    assert_eq!(jwt_claims(requests[1].dpop.as_ref().unwrap())["nonce"], "server-nonce");
+    idc.stop();
+}
+
+fn with_par(url: &str) -> Value {
+    json!({ "pushed_authorization_request_endpoint": format!("{}/par", url) })
+}
+
+#[test]
+fn pushed_authorization_request() {
+    let issuer = FakeIssuer::start_with(with_par);
+    let mut idc = issuer.client();
+    // nothing is pushed until the URL is needed
+    assert!(issuer.pushed_requests.lock().unwrap().is_empty());
+    assert_eq!(
+        idc.auth_url(),
+        format!(
+            "{}/authorize?client_id={}&request_uri=urn%3Aietf%3Aparams%3Aoauth%3Arequest_uri%3A1",
+            issuer.url, CLIENT_ID
+        )
+    );
+
+    let pushed = issuer.pushed_requests.lock().unwrap()[0].clone();
+    let param = |name: &str| pushed.get(name).map(String::as_str);
+    assert_eq!(param("client_id"), Some(CLIENT_ID));
+    assert_eq!(param("response_type"), Some("code"));
+    assert_eq!(param("state"), Some(STATE));
+    assert_eq!(param("nonce"), Some(NONCE));
+    assert_eq!(param("redirect_uri"), Some("http://localhost:9993/sso"));
+    assert_eq!(param("code_challenge_method"), Some("S256"));
+
+    // the code is exchanged with the verifier for the pushed challenge
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    idc.do_token_exchange("code").unwrap();
+    assert!(idc.is_running());
+    idc.stop();
+}
+
+#[test]
+fn pushed_authorization_request_expired() {
+    let issuer = FakeIssuer::start_with(with_par);
+    *issuer.par_expires_in.lock().unwrap() = 1;
+    let idc = issuer.client();
+    let first = idc.auth_url();
+    let second = idc.auth_url();
+    assert_ne!(first, second);
+
+    // the same request is pushed again
+    let pushed = issuer.pushed_requests.lock().unwrap().clone();
+    assert_eq!(pushed.len(), 2);
+    assert_eq!(pushed[0].get("code_challenge"), pushed[1].get("code_challenge"));
+    assert_eq!(pushed[0].get("state"), pushed[1].get("state"));
+}
+
+#[test]
+fn pushed_authorization_request_reused() {
+    let issuer = FakeIssuer::start_with(with_par);
+    let mut idc = issuer.client();
+    let url = idc.auth_url();
+    assert_eq!(idc.auth_url(), url);
+    assert_eq!(issuer.pushed_requests.lock().unwrap().len(), 1);
+
+    // a new state means a new request
+    idc.set_nonce_and_csrf("other-state_8056c2e21c000001".to_string(), NONCE.to_string());
+    assert_ne!(idc.auth_url(), url);
+    assert_eq!(issuer.pushed_requests.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn pushed_authorization_required_without_endpoint() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    let profiles = json!({ "default": { "require_pushed_authorization_requests": true } });
+    assert!(idc.load_provider_profiles(&profiles.to_string()).unwrap());
+    assert_eq!(idc.auth_url(), "");
+}
+
+#[test]
+fn http_config_user_agent() {
+    let issuer = FakeIssuer::start();
+    let config = HttpConfig::from_json(r#"{ "user_agent": "zt-test/1.0", "timeout": 10 }"#).unwrap();
+    let central = format!("{}/central", issuer.url);
+    let mut idc = ZeroIDC::with_http_config(&issuer.url, CLIENT_ID, "default", &central, 9993, &config).unwrap();
+    idc.set_nonce_and_csrf(STATE.to_string(), NONCE.to_string());
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    idc.do_token_exchange("code").unwrap();
+
+    assert_eq!(issuer.last_token_request().user_agent.as_deref(), Some("zt-test/1.0"));
+    idc.stop();
+}
+
+#[test]
+fn http_config_missing_ca_file() {
+    let issuer = FakeIssuer::start();
+    let config = HttpConfig {
+        ca_files: vec!["/nonexistent/ca.pem".into()],
+        ..Default::default()
+    };
+    let central = format!("{}/central", issuer.url);
+    match ZeroIDC::with_http_config(&issuer.url, CLIENT_ID, "default", &central, 9993, &config) {
+        Err(ZeroIDCError::HttpConfig(HttpConfigError::CaFile { .. })) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("client created with a missing CA file"),
+    }
+}
+
+#[test]
+fn http_config_client_cert() {
+    let testdata = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    let config = HttpConfig {
+        client_cert: Some(testdata.join("client-cert.pem")),
+        client_key: Some(testdata.join("client-key.pem")),
+        ..Default::default()
+    };
+    assert!(HttpClient::new(&config).is_ok());
+
+    let config = HttpConfig { client_key: None, ..config };
+    assert!(matches!(
+        HttpClient::new(&config),
+        Err(HttpConfigError::IncompleteClientCert)
+    ));
+}
+
+#[test]
+fn refresh_lead_and_jitter() {
+    let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+    let default = TimingConfig::default();
+    assert_eq!(default.refresh_due(1000, 4600, 0), at(4570));
+    // the IdP's clock is 100s ahead of ours
+    assert_eq!(default.refresh_due(1000, 4600, 100), at(4470));
+
+    let percent = TimingConfig { refresh_lead: RefreshLead::Percent(25), ..Default::default() };
+    assert_eq!(percent.refresh_due(1000, 4600, 0), at(3700));
+    // without an issue time the lifetime is unknown
+    assert_eq!(percent.refresh_due(0, 4600, 0), at(4570));
+
+    // never more than half the lifetime
+    let long = TimingConfig {
+        refresh_lead: RefreshLead::Seconds(3000),
+        ..Default::default()
+    };
+    assert_eq!(long.refresh_due(1000, 4600, 0), at(2800));
+
+    let jitter = TimingConfig { refresh_jitter: 60, ..Default::default() };
+    for _ in 0..20 {
+        let due = jitter.refresh_due(1000, 4600, 0);
+        assert!(due >= at(4510) && due <= at(4570));
+    }
+}
+
+#[test]
+fn clock_skew_tolerance() {
+    let issuer = FakeIssuer::start();
+    let token = issuer.sign(&issuer.claims(Some(NONCE), -30));
+    let (_, err) = exchange_error(&issuer, Some(token.clone()));
+    assert!(matches!(
+        err,
+        SSOExchangeError::Session(SessionError::TokenVerification(_))
+    ));
+
+    let mut idc = issuer.client();
+    idc.set_timing(TimingConfig::from_json(r#"{ "clock_skew": 120 }"#).unwrap());
+    issuer.queue_token_response(200, token_response(Some(token)));
+    idc.do_token_exchange("code").unwrap();
+    idc.stop();
+}
+
+#[test]
+fn clock_offset_in_status() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    let offset = idc.status().clock_offset.expect("no clock offset measured");
+    assert!(offset.abs() <= 2, "offset {}", offset);
+    idc.stop();
+}
+
+#[test]
+fn claims_after_login() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.client();
+    let profiles = json!({ "default": { "claims": ["groups", "roles"] } });
+    assert!(idc.load_provider_profiles(&profiles.to_string()).unwrap());
+    assert!(idc.claims().is_none());
+
+    let mut claims = issuer.claims(Some(NONCE), 3600);
+    claims["email"] = json!("alice@example.com");
+    claims["email_verified"] = json!(true);
+    claims["name"] = json!("Alice");
+    claims["groups"] = json!(["admins", "devs"]);
+    claims["department"] = json!("engineering");
+    issuer.queue_token_response(200, token_response(Some(issuer.sign(&claims))));
+    idc.do_token_exchange("code").unwrap();
+
+    let claims = idc.claims().unwrap();
+    assert_eq!(claims.subject, "user");
+    assert_eq!(claims.issuer, issuer.url);
+    assert_eq!(claims.email.as_deref(), Some("alice@example.com"));
+    assert_eq!(claims.email_verified, Some(true));
+    assert_eq!(claims.name.as_deref(), Some("Alice"));
+    assert_eq!(claims.preferred_username, None);
+    assert_eq!(claims.extra.len(), 1);
+    assert_eq!(claims.extra["groups"], json!(["admins", "devs"]));
+
+    idc.logout();
+    assert!(idc.claims().is_none());
+}
+
+/// Exchanges a token with extra `claims` under `policy`.
+fn exchange_with_policy(issuer: &FakeIssuer, policy: &Value, claims: Value) -> Result<ZeroIDC, SSOExchangeError> {
+    let mut idc = issuer.client();
+    idc.set_admission_policy(AdmissionPolicy::from_json(&policy.to_string()).unwrap());
+    let mut token = issuer.claims(Some(NONCE), 3600);
+    if let (Some(t), Value::Object(extra)) = (token.as_object_mut(), claims) {
+        t.extend(extra);
+    }
+    issuer.queue_token_response(200, token_response(Some(issuer.sign(&token))));
+    idc.do_token_exchange("code").map(|_| idc)
+}
+
+fn policy_violation(res: Result<ZeroIDC, SSOExchangeError>) -> PolicyViolation {
+    match res {
+        Err(SSOExchangeError::Session(SessionError::PolicyRejected(v))) => v,
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("policy passed"),
+    }
+}
+
+#[test]
+fn policy_groups_and_email() {
+    let issuer = FakeIssuer::start();
+    let policy = json!({ "required_groups": ["zt"], "allowed_email_domains": ["example.com"] });
+
+    let res = exchange_with_policy(
+        &issuer,
+        &policy,
+        json!({ "groups": ["other"], "email": "a@example.com" }),
+    );
+    assert_eq!(policy_violation(res), PolicyViolation::MissingGroup);
+
+    let res = exchange_with_policy(&issuer, &policy, json!({ "groups": "zt", "email": "a@evil.com" }));
+    assert_eq!(
+        policy_violation(res),
+        PolicyViolation::EmailDomain(Some("evil.com".to_string()))
+    );
+
+    let claims = json!({ "groups": ["zt"], "email": "a@Example.com", "email_verified": false });
+    let res = exchange_with_policy(&issuer, &policy, claims);
+    assert_eq!(policy_violation(res), PolicyViolation::EmailNotVerified);
+
+    let res = exchange_with_policy(&issuer, &policy, json!({ "groups": ["zt"], "email": "a@Example.com" }));
+    let mut idc = res.unwrap();
+    assert!(idc.is_running());
+    idc.stop();
+}
+
+#[test]
+fn policy_authentication_strength() {
+    let issuer = FakeIssuer::start();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let policy = json!({ "allowed_acr": ["mfa"], "required_amr": ["otp"], "max_auth_age": 600 });
+
+    let res = exchange_with_policy(&issuer, &policy, json!({ "amr": ["otp"], "auth_time": now }));
+    assert_eq!(policy_violation(res), PolicyViolation::Acr(None));
+
+    let res = exchange_with_policy(
+        &issuer,
+        &policy,
+        json!({ "acr": "mfa", "amr": ["pwd"], "auth_time": now }),
+    );
+    assert_eq!(policy_violation(res), PolicyViolation::Amr("otp".to_string()));
+
+    let res = exchange_with_policy(&issuer, &policy, json!({ "acr": "mfa", "amr": ["otp"] }));
+    assert_eq!(policy_violation(res), PolicyViolation::MissingAuthTime);
+
+    let claims = json!({ "acr": "mfa", "amr": ["otp"], "auth_time": now - 3600 });
+    let res = exchange_with_policy(&issuer, &policy, claims);
+    assert!(matches!(policy_violation(res), PolicyViolation::AuthTooOld(_)));
+
+    let claims = json!({ "acr": "mfa", "amr": ["pwd", "otp"], "auth_time": now });
+    let mut idc = exchange_with_policy(&issuer, &policy, claims).unwrap();
+    let url = url::Url::parse(&idc.auth_url()).unwrap();
+    let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.into_owned());
+    assert_eq!(param("acr_values").as_deref(), Some("mfa"));
+    assert_eq!(param("max_age").as_deref(), Some("600"));
+
+    // the refreshed token still has to pass
+    let mut claims = issuer.claims(None, 3600);
+    claims["acr"] = json!("pwd");
+    issuer.queue_token_response(200, token_response(Some(issuer.sign(&claims))));
+    assert_eq!(refresh_session(&idc.inner), None);
+    assert!(!idc.is_running());
+    assert_eq!(idc.state(), SessionState::Expired);
+    idc.stop();
+}
+
+#[test]
+fn typed_central_response() {
+    let issuer = FakeIssuer::start();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    issuer.set_central_response(
+        200,
+        json!({
+            "networkId": "8056c2e21c000001",
+            "memberId": "a1b2c3d4e5",
+            "authenticationExpiryTime": (now + 600) * 1000,
+        }),
+    );
+    let mut idc = issuer.client();
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    let central = idc.do_token_exchange("code").unwrap();
+    assert_eq!(central.network_id_u64(), 0x8056c2e21c000001);
+    assert_eq!(central.member_id_u64(), 0xa1b2c3d4e5);
+    assert_eq!(central.exp_time(), Some(now + 600));
+    assert_eq!(idc.status().central_expires, Some(now + 600));
+
+    // central's authorization runs out well before the ID token
+    let due = idc.inner.lock().unwrap().refresh_due();
+    assert!(due <= UNIX_EPOCH + Duration::from_secs(now + 600));
+
+    // central doesn't always say
+    issuer.set_central_response(200, json!({}));
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert!(refresh_session(&idc.inner).is_some());
+    assert_eq!(idc.status().central_expires, None);
+    assert!(idc.inner.lock().unwrap().refresh_due() > UNIX_EPOCH + Duration::from_secs(now + 3000));
+    idc.stop();
+}
+
+#[test]
+fn invalid_central_response() {
+    let issuer = FakeIssuer::start();
+    issuer.set_central_response(200, json!({ "networkId": "8056c2e21c000002" }));
+    let (_, err) = exchange_error(&issuer, Some(issuer.valid_id_token(Some(NONCE))));
+    assert!(matches!(err, SSOExchangeError::InvalidCentralResponse(_)));
+
+    issuer.set_central_response(200, json!({ "memberId": "zz" }));
+    let (_, err) = exchange_error(&issuer, Some(issuer.valid_id_token(Some(NONCE))));
+    assert!(matches!(err, SSOExchangeError::InvalidCentralResponse(_)));
+}
+
+#[test]
+fn central_error_reason() {
+    let issuer = FakeIssuer::start();
+    issuer.set_central_response(403, json!({ "error": "forbidden", "reason": "member is banned" }));
+    let (_, err) = exchange_error(&issuer, Some(issuer.valid_id_token(Some(NONCE))));
+    match err {
+        SSOExchangeError::Central { status, reason } => {
+            assert_eq!(status, 403);
+            assert_eq!(reason.as_deref(), Some("forbidden: member is banned"));
+        }
+        e => panic!("unexpected error: {}", e),
+    }
+
+    issuer.set_central_response(200, json!({}));
+    let mut idc = issuer.logged_in();
+    issuer.set_central_response(403, json!({ "reason": "member is banned" }));
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert_eq!(refresh_session(&idc.inner), None);
+    match idc.inner.lock().unwrap().last_error.clone() {
+        Some(SessionError::RefreshRejected(reason)) => assert!(reason.contains("member is banned"), "{}", reason),
+        e => panic!("unexpected error: {:?}", e),
+    }
+    idc.stop();
+}
+
+/// An empty directory to use as zerotier-one's home.
+fn temp_home(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("zeroidc-{}-{:016x}", name, rand::random::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn stored_session(store: &SessionStore) -> StoredSession {
+    StoredSession {
+        issuer: store.issuer().to_string(),
+        client_id: store.client_id().to_string(),
+        network_id: store.network_id().to_string(),
+        refresh_token: Secret::new("refresh".to_string()),
+        exp_time: 1234,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn stored_session_round_trip() {
+    let home = temp_home("session");
+    let store = SessionStore::new(&home, "https://idp", CLIENT_ID, "8056c2e21c000001");
+    assert!(store.load().is_none());
+    store.save(&stored_session(&store));
+
+    let raw = fs::read_to_string(home.join("sso.d/8056c2e21c000001.session")).unwrap();
+    assert!(!raw.contains("refresh"));
+    let session = store.load().unwrap();
+    assert_eq!(session.refresh_token.expose(), "refresh");
+    assert_eq!(session.exp_time, 1234);
+
+    // a session for another issuer, client or network is thrown away
+    for other in [
+        SessionStore::new(&home, "https://other-idp", CLIENT_ID, "8056c2e21c000001"),
+        SessionStore::new(&home, "https://idp", "other-client", "8056c2e21c000001"),
+    ] {
+        store.save(&stored_session(&store));
+        assert!(other.load().is_none());
+        assert!(store.load().is_none());
+    }
+    store.save(&stored_session(&store));
+    let session_path = home.join("sso.d/8056c2e21c000001.session");
+    fs::copy(&session_path, home.join("sso.d/8056c2e21c000002.session")).unwrap();
+    let other = SessionStore::new(&home, "https://idp", CLIENT_ID, "8056c2e21c000002");
+    assert!(other.load().is_none());
+    assert!(!home.join("sso.d/8056c2e21c000002.session").exists());
+    assert!(store.load().is_some());
+
+    fs::remove_dir_all(home).unwrap();
+}
+
+#[test]
+fn stored_session_corrupted() {
+    let home = temp_home("session-corrupted");
+    let store = SessionStore::new(&home, "https://idp", CLIENT_ID, "8056c2e21c000001");
+    store.save(&stored_session(&store));
+
+    let path = home.join("sso.d/8056c2e21c000001.session");
+    let mut sealed: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    let mut ciphertext = base64::engine::general_purpose::STANDARD
+        .decode(sealed["ciphertext"].as_str().unwrap())
+        .unwrap();
+    ciphertext[0] ^= 1;
+    sealed["ciphertext"] = json!(base64::engine::general_purpose::STANDARD.encode(ciphertext));
+    fs::write(&path, sealed.to_string()).unwrap();
+
+    assert!(store.load().is_none());
+    assert!(!path.exists());
+
+    fs::remove_dir_all(home).unwrap();
+}
+
+#[test]
+fn session_key_is_never_replaced() {
+    let home = temp_home("session-key");
+    let stores: Vec<SessionStore> = (1..=8)
+        .map(|n| SessionStore::new(&home, "https://idp", CLIENT_ID, &format!("8056c2e21c00000{}", n)))
+        .collect();
+
+    // every network saving its first session at once ends up with the same key
+    std::thread::scope(|scope| {
+        for store in stores.iter() {
+            scope.spawn(move || store.save(&stored_session(store)));
+        }
+    });
+    for store in stores.iter() {
+        assert!(store.load().is_some());
+    }
+    let leftovers = fs::read_dir(home.join("sso.d"))
+        .unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "tmp")
+        .count();
+    assert_eq!(leftovers, 0);
+
+    // a damaged key is an error, not a reason to make a new one
+    let key_path = home.join("sso.d/session.key");
+    fs::write(&key_path, b"short").unwrap();
+    stores[0].save(&stored_session(&stores[0]));
+    assert_eq!(fs::read(&key_path).unwrap(), b"short");
+    assert!(stores[1].load().is_none());
+    assert!(home.join("sso.d/8056c2e21c000002.session").exists());
+
+    fs::remove_dir_all(home).unwrap();
+}
+
+#[test]
+fn jwks_key_rotation() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    let fetches = issuer.jwks_fetches();
+    // discovery fetched the keys just now
+    idc.inner.lock().unwrap().jwks.set_min_refetch_interval(Duration::ZERO);
+
+    // a token signed with a key we haven't seen makes us fetch the keys again, and the
+    // new set is cached as long as the IdP says
+    issuer.publish_keys(&[KID, "rotated"], Some("public, max-age=300"));
+    let token = issuer.sign_with_kid("rotated", &issuer.claims(None, 3600));
+    issuer.queue_token_response(200, token_response(Some(token)));
+    assert!(refresh_session(&idc.inner).is_some());
+    assert_eq!(issuer.jwks_fetches(), fetches + 1);
+    let expires_in = idc.inner.lock().unwrap().jwks.expires() - Instant::now();
+    assert!(expires_in > Duration::from_secs(290) && expires_in <= Duration::from_secs(300));
+
+    // but not over and over for tokens with made up key IDs
+    idc.inner
+        .lock()
+        .unwrap()
+        .jwks
+        .set_min_refetch_interval(Duration::from_secs(60));
+    issuer.publish_keys(&[KID, "rotated", "bogus"], None);
+    let token = issuer.sign_with_kid("bogus", &issuer.claims(None, 3600));
+    issuer.queue_token_response(200, token_response(Some(token)));
+    assert!(refresh_session(&idc.inner).is_none());
+    assert_eq!(issuer.jwks_fetches(), fetches + 1);
+    assert!(matches!(
+        idc.inner.lock().unwrap().last_error,
+        Some(SessionError::TokenVerification(_))
+    ));
+    idc.stop();
+}
+
+#[test]
+fn jwks_fetched_without_session_locked() {
+    let issuer = FakeIssuer::start();
+    let mut idc = issuer.logged_in();
+    let fetches = issuer.jwks_fetches();
+    idc.inner.lock().unwrap().jwks.set_min_refetch_interval(Duration::ZERO);
+    issuer.publish_keys(&[KID, "rotated"], None);
+    *issuer.jwks_delay.lock().unwrap() = Duration::from_secs(2);
+    let token = issuer.sign_with_kid("rotated", &issuer.claims(None, 3600));
+    issuer.queue_token_response(200, token_response(Some(token)));
+
+    let inner = Arc::clone(&idc.inner);
+    let refresh = spawn(move || refresh_session(&inner));
+    assert!(wait_until(|| issuer.jwks_fetches() > fetches));
+    // still fetching the keys, but the session can be looked at
+    let start = Instant::now();
+    assert_eq!(idc.status().state, SessionState::Refreshing);
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    assert!(refresh.join().unwrap().is_some());
+    assert_eq!(idc.state(), SessionState::Active);
+    idc.stop();
+}
+
+#[test]
+fn jwks_cache_lifetime() {
+    let headers = |pairs: &[(&'static str, &str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    };
+    let secs = Duration::from_secs;
+
+    assert_eq!(cache_lifetime(&headers(&[])), secs(3600));
+    assert_eq!(cache_lifetime(&headers(&[("cache-control", "max-age=600")])), secs(600));
+    assert_eq!(
+        cache_lifetime(&headers(&[("cache-control", "public, max-age=600"), ("age", "100")])),
+        secs(500)
+    );
+    assert_eq!(
+        cache_lifetime(&headers(&[("cache-control", "max-age=999999")])),
+        secs(86400)
+    );
+    assert_eq!(
+        cache_lifetime(&headers(&[("cache-control", "no-store, max-age=600")])),
+        secs(0)
+    );
+
+    let expires = httpdate::fmt_http_date(SystemTime::now() + secs(120));
+    let lifetime = cache_lifetime(&headers(&[("expires", &expires)]));
+    assert!(lifetime > secs(110) && lifetime <= secs(120));
+    // max-age wins over Expires
+    assert_eq!(
+        cache_lifetime(&headers(&[("cache-control", "max-age=60"), ("expires", &expires)])),
+        secs(60)
+    );
+}
+
+/// A session for a scheduler of its own that records when it's refreshed.
+struct Probe {
+    id: u64,
+    log: Arc<Mutex<Vec<u64>>>,
+    /// How long a refresh takes.
+    delay: Duration,
+    /// When the next refresh is due after this one.
+    next: Option<SystemTime>,
+    /// Refreshes running right now, and the most there ever were at once.
+    running: AtomicUsize,
+    max_running: AtomicUsize,
+}
+
+fn refresh_probe(probe: &Arc<Probe>) -> Option<SystemTime> {
+    let running = probe.running.fetch_add(1, Ordering::SeqCst) + 1;
+    probe.max_running.fetch_max(running, Ordering::SeqCst);
+    std::thread::sleep(probe.delay);
+    probe.log.lock().unwrap().push(probe.id);
+    probe.running.fetch_sub(1, Ordering::SeqCst);
+    probe.next
+}
+
+fn probe(id: u64, log: &Arc<Mutex<Vec<u64>>>, delay: Duration, next: Option<SystemTime>) -> Arc<Probe> {
+    Arc::new(Probe {
+        id,
+        log: Arc::clone(log),
+        delay,
+        next,
+        running: AtomicUsize::new(0),
+        max_running: AtomicUsize::new(0),
+    })
+}
+
+/// Waits up to 5 seconds for `cond`.
+fn wait_until(cond: impl Fn() -> bool) -> bool {
+    for _ in 0..100 {
+        if cond() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[test]
+fn scheduler_order() {
+    let scheduler = Scheduler::start(refresh_probe);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let now = SystemTime::now();
+    let probes: Vec<Arc<Probe>> = (1..=3).map(|id| probe(id, &log, Duration::ZERO, None)).collect();
+    scheduler.schedule(1, &probes[0], now + Duration::from_millis(600));
+    scheduler.schedule(2, &probes[1], now + Duration::from_millis(200));
+    scheduler.schedule(3, &probes[2], now + Duration::from_millis(400));
+
+    assert!(wait_until(|| log.lock().unwrap().len() == 3));
+    assert_eq!(*log.lock().unwrap(), vec![2, 3, 1]);
+    // sessions that ended are forgotten
+    assert!(wait_until(|| (1..=3).all(|id| !scheduler.is_scheduled(id))));
+}
+
+#[test]
+fn scheduler_slow_session() {
+    let scheduler = Scheduler::start(refresh_probe);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let now = SystemTime::now();
+    let slow = probe(1, &log, Duration::from_secs(3), None);
+    let fast = probe(2, &log, Duration::ZERO, None);
+    scheduler.schedule(1, &slow, now);
+    scheduler.schedule(2, &fast, now + Duration::from_millis(100));
+
+    // the slow IdP doesn't hold up the other session
+    assert!(wait_until(|| !log.lock().unwrap().is_empty()));
+    assert_eq!(*log.lock().unwrap(), vec![2]);
+    assert!(wait_until(|| log.lock().unwrap().len() == 2));
+}
+
+#[test]
+fn scheduler_kick() {
+    let scheduler = Scheduler::start(refresh_probe);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let later = SystemTime::now() + Duration::from_secs(3600);
+    let p = probe(1, &log, Duration::from_millis(300), Some(later));
+    scheduler.schedule(1, &p, later);
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(log.lock().unwrap().is_empty());
+
+    scheduler.schedule(1, &p, SystemTime::now());
+    assert!(wait_until(|| log.lock().unwrap().len() == 1));
+    assert!(scheduler.is_scheduled(1));
+
+    // a kick during a refresh isn't lost
+    scheduler.schedule(1, &p, SystemTime::now());
+    std::thread::sleep(Duration::from_millis(100));
+    scheduler.schedule(1, &p, SystemTime::now());
+    assert!(wait_until(|| log.lock().unwrap().len() == 3));
+    scheduler.cancel(1);
+    assert!(!scheduler.is_scheduled(1));
+}
+
+#[test]
+fn scheduler_one_refresh_at_a_time() {
+    let scheduler = Scheduler::start(refresh_probe);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let p = probe(
+        1,
+        &log,
+        Duration::from_millis(200),
+        Some(SystemTime::now() + Duration::from_secs(3600)),
+    );
+
+    // kicked over and over, as zerotier-one does on every config update while the
+    // network wants authentication
+    for _ in 0..50 {
+        scheduler.schedule(1, &p, SystemTime::now());
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert!(wait_until(|| p.running.load(Ordering::SeqCst) == 0));
+    assert_eq!(p.max_running.load(Ordering::SeqCst), 1);
+    // the kicks weren't lost, just held until the refresh in flight was done
+    assert!(log.lock().unwrap().len() >= 4);
+
+    // neither is a cancel while a refresh is in flight
+    let done = log.lock().unwrap().len();
+    scheduler.schedule(1, &p, SystemTime::now());
+    assert!(wait_until(|| p.running.load(Ordering::SeqCst) == 1));
+    scheduler.cancel(1);
+    assert!(!scheduler.is_scheduled(1));
+    scheduler.schedule(1, &p, SystemTime::now());
+    assert!(wait_until(|| log.lock().unwrap().len() == done + 2));
+    assert_eq!(p.max_running.load(Ordering::SeqCst), 1);
+    scheduler.cancel(1);
+}
+
+#[test]
+fn scheduler_drops_sessions() {
+    let scheduler = Scheduler::start(refresh_probe);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let p = probe(1, &log, Duration::ZERO, Some(SystemTime::now()));
+    scheduler.schedule(1, &p, SystemTime::now() + Duration::from_millis(200));
+    drop(p);
+
+    assert!(wait_until(|| !scheduler.is_scheduled(1)));
+    assert!(log.lock().unwrap().is_empty());
+}
+
+#[test]
+fn secret_is_redacted() {
+    let secret = Secret::new("hunter2".to_string());
+    assert_eq!(secret.to_string(), "[redacted]");
+    assert_eq!(format!("{:?}", secret), "Secret([redacted])");
+    assert!(!format!("{:?}", Some(&secret)).contains("hunter2"));
+    assert_eq!(secret.expose(), "hunter2");
+}
+
+#[test]
+fn secret_comparison() {
+    let secret = Secret::new(STATE.to_string());
+    assert!(secret.matches(STATE));
+    assert!(!secret.matches(&STATE[1..]));
+    assert!(!secret.matches(&format!("{}x", STATE)));
+    assert!(!secret.matches(""));
+    assert!(bool::from(secret.ct_eq(&secret.clone())));
+    assert!(!bool::from(secret.ct_eq(&Secret::new("something-else".to_string()))));
+}
+
+#[test]
+fn secret_is_zeroized() {
+    fn zeroized_on_drop<T: ZeroizeOnDrop>() {}
+    zeroized_on_drop::<Secret>();
+
+    let mut secret = Secret::new("hunter2".to_string());
+    secret.zeroize();
+    assert!(secret.is_empty());
+    // the buffer is still ours, and wiped
+    let wiped = unsafe { std::slice::from_raw_parts(secret.expose().as_ptr(), 7) };
+    assert_eq!(wiped, [0u8; 7]);
+}
+
+#[test]
+fn central_follows_redirects() {
+    let issuer = FakeIssuer::start();
+    issuer.set_central_response(200, json!({ "networkId": "8056c2e21c000001" }));
+    let central = format!("{}/moved/central", issuer.url);
+    let mut idc = ZeroIDC::new(&issuer.url, CLIENT_ID, "default", &central, 9993).unwrap();
+    idc.set_nonce_and_csrf(STATE.to_string(), NONCE.to_string());
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    let res = idc.do_token_exchange("code").unwrap();
+    assert_eq!(res.network_id_u64(), 0x8056c2e21c000001);
+
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    assert!(refresh_session(&idc.inner).is_some());
+    assert!(idc.last_error().is_none());
+    idc.stop();
+}
+
+#[test]
+fn idp_redirects_are_not_followed() {
+    let issuer = FakeIssuer::start_with(|url| json!({ "token_endpoint": format!("{}/moved/token", url) }));
+    let mut idc = issuer.client();
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(Some(NONCE)))));
+    assert!(idc.do_token_exchange("code").is_err());
+    assert!(!idc.is_running());
+    // the token endpoint never saw the code
+    assert!(issuer.token_requests.lock().unwrap().is_empty());
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Refreshes with central saying the member is authorized until `central_exp`, and
+/// returns when the next refresh is due.
+fn refresh_with_central_exp(issuer: &FakeIssuer, idc: &ZeroIDC, central_exp: u64) -> SystemTime {
+    issuer.set_central_response(200, json!({ "authenticationExpiryTime": central_exp * 1000 }));
+    issuer.queue_token_response(200, token_response(Some(issuer.valid_id_token(None))));
+    refresh_session(&idc.inner).unwrap()
+}
+
+#[test]
+fn central_expiry_in_the_past() {
+    let issuer = FakeIssuer::start();
+    let now = unix_now();
+    issuer.set_central_response(200, json!({ "authenticationExpiryTime": (now - 60) * 1000 }));
+    let mut idc = issuer.logged_in();
+    assert_eq!(idc.status().central_expires, Some(now - 60));
+
+    // refreshing right away wouldn't get us anywhere
+    let token_due = UNIX_EPOCH + Duration::from_secs(now + 3000);
+    assert!(idc.inner.lock().unwrap().refresh_due() > token_due);
+    assert!(refresh_with_central_exp(&issuer, &idc, now - 30) > token_due);
+    idc.stop();
+}
+
+#[test]
+fn central_expiry_not_extended() {
+    let issuer = FakeIssuer::start();
+    let now = unix_now();
+    issuer.set_central_response(200, json!({ "authenticationExpiryTime": (now + 600) * 1000 }));
+    let mut idc = issuer.logged_in();
+    let central_due = UNIX_EPOCH + Duration::from_secs(now + 600);
+    assert!(idc.inner.lock().unwrap().refresh_due() <= central_due);
+
+    // the refresh didn't buy any time with central, so the next one waits for the token
+    assert!(refresh_with_central_exp(&issuer, &idc, now + 600) > UNIX_EPOCH + Duration::from_secs(now + 3000));
+    // until one does
+    assert!(refresh_with_central_exp(&issuer, &idc, now + 1200) <= UNIX_EPOCH + Duration::from_secs(now + 1200));
+    idc.stop();
+}
+
+#[test]
+fn minimum_refresh_interval() {
+    let issuer = FakeIssuer::start();
+    let now = unix_now();
+    issuer.set_central_response(200, json!({ "authenticationExpiryTime": (now + 10) * 1000 }));
+    let mut idc = issuer.logged_in();
+    assert!(idc.inner.lock().unwrap().refresh_due() <= UNIX_EPOCH + Duration::from_secs(now + 10));
+
+    // central only ever gives us a few more seconds
+    let next = refresh_with_central_exp(&issuer, &idc, now + 20);
+    assert!(next >= SystemTime::now() + Duration::from_secs(29), "{:?}", next);
+    idc.stop();
+}
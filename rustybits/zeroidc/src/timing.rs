@@ -0,0 +1,121 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Clock skew tolerance and when to refresh.
+//!
+//! Nodes without a reliable clock, like embedded boxes without an RTC, can be told to
+//! accept ID tokens that look a little expired and to refresh well ahead of expiry:
+//!
+//! ```json
+//! {
+//!     "clock_skew": 120,
+//!     "refresh_lead": { "percent": 20 },
+//!     "refresh_jitter": 30
+//! }
+//! ```
+//!
+//! All times are in seconds.  `refresh_lead` is either `{ "seconds": n }` or a
+//! percentage of the ID token's lifetime.  Up to `refresh_jitter` more seconds are taken
+//! off at random so nodes that logged in together don't all refresh at once.  The
+//! defaults are what zeroidc has always done: no skew and a 30 second lead.
+//!
+//! Independently of the configuration, the `Date` header of the IdP's responses tells
+//! us how far our clock is off.  Refreshes are scheduled by the IdP's clock, and the
+//! offset is reported in the session status.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use openidconnect::http::HeaderValue;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// An offset worth warning about.
+pub const LARGE_CLOCK_OFFSET: i64 = 60;
+
+const DEFAULT_REFRESH_LEAD: u64 = 30;
+
+/// Shortest time between two refreshes of a session, whatever the ID token and central
+/// say.
+pub const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshLead {
+    Seconds(u64),
+    /// Percentage of the ID token's lifetime, from `iat` to `exp`.
+    Percent(u8),
+}
+
+impl Default for RefreshLead {
+    fn default() -> Self {
+        RefreshLead::Seconds(DEFAULT_REFRESH_LEAD)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct TimingConfig {
+    /// How far past its `exp` an ID token is still accepted.
+    pub clock_skew: u64,
+    pub refresh_lead: RefreshLead,
+    pub refresh_jitter: u64,
+}
+
+impl TimingConfig {
+    pub fn from_json(json: &str) -> Result<TimingConfig, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn clock_skew(&self) -> Duration {
+        Duration::from_secs(self.clock_skew)
+    }
+
+    /// When to refresh an ID token issued at `issued` and expiring at `exp`, both by
+    /// the IdP's clock, which is `offset` seconds ahead of ours.  `issued` is 0 if
+    /// unknown.
+    ///
+    /// Lead and jitter together never take up more than half the token's lifetime, so
+    /// short-lived tokens don't get refreshed over and over.
+    pub fn refresh_due(&self, issued: u64, exp: u64, offset: i64) -> SystemTime {
+        let lifetime = if issued > 0 && issued < exp {
+            Some(exp - issued)
+        } else {
+            None
+        };
+        let lead = match (self.refresh_lead, lifetime) {
+            (RefreshLead::Seconds(s), _) => s,
+            (RefreshLead::Percent(p), Some(lifetime)) => lifetime * u64::from(p.min(100)) / 100,
+            (RefreshLead::Percent(_), None) => DEFAULT_REFRESH_LEAD,
+        };
+        let jitter = match self.refresh_jitter {
+            0 => 0,
+            j => rand::thread_rng().gen_range(0..=j),
+        };
+        let before = match lifetime {
+            Some(lifetime) => (lead + jitter).min(lifetime / 2),
+            None => lead + jitter,
+        };
+
+        let due = (exp as i64).saturating_sub(offset).saturating_sub(before as i64);
+        UNIX_EPOCH + Duration::from_secs(due.max(0) as u64)
+    }
+}
+
+/// How many seconds the clock of a server whose response carried `date` is ahead of
+/// ours.
+pub fn offset_from_date(date: &HeaderValue, now: SystemTime) -> Option<i64> {
+    let server = httpdate::parse_http_date(date.to_str().ok()?).ok()?;
+    Some(match server.duration_since(now) {
+        Ok(ahead) => ahead.as_secs() as i64,
+        Err(behind) => -(behind.duration().as_secs() as i64),
+    })
+}
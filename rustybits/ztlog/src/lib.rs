@@ -0,0 +1,350 @@
+/*
+ * Copyright (c)2023 ZeroTier, Inc.
+ *
+ * Use of this software is governed by the Business Source License included
+ * in the LICENSE.TXT file in the project's root directory.
+ *
+ * Change Date: 2026-01-01
+ *
+ * On the date above, in accordance with the Business Source License, use
+ * of this software will be governed by version 2.0 of the Apache License.
+ */
+
+//! Logging for the rustybits libraries.
+//!
+//! The libraries log through `tracing`.  When they're linked into zerotier-one, the FFI
+//! entry points call [`init`] to install a subscriber that formats each record on one
+//! line, with the fields of the spans it happened in, and hands it to the sink set with
+//! [`set_sink`].  Records are dropped while there is no sink.  Rust programs using the
+//! libraries directly can install their own subscriber instead.
+//!
+//! Secrets are redacted before a record leaves this crate: values of fields named like
+//! `refresh_token` or `code`, JWTs anywhere in a message, and secret query or JSON
+//! parameters, such as the `code` in a redirect URL that ended up in an error.
+
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once, RwLock};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+pub use tracing::level_filters::LevelFilter;
+
+#[cfg(test)]
+mod tests;
+
+/// What secrets are replaced with.
+pub const REDACTED: &str = "[redacted]";
+
+/// Receives each record's level, target and formatted, redacted message.
+pub type LogSink = Arc<dyn Fn(Level, &str, &str) + Send + Sync>;
+
+static INIT: Once = Once::new();
+static SINK: RwLock<Option<LogSink>> = RwLock::new(None);
+/// 0, logging off, until there's a sink.
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs the global subscriber, unless something else already has.  Safe to call
+/// any number of times.
+pub fn init() {
+    INIT.call_once(|| {
+        let _ = tracing::subscriber::set_global_default(Registry::default().with(HostLayer));
+    });
+}
+
+/// Sends records up to `max_level` to `sink`, or drops them all if `sink` is `None`.
+/// Once this returns the previous sink won't be called again.
+pub fn set_sink(sink: Option<LogSink>, max_level: LevelFilter) {
+    init();
+    let max_level = match sink {
+        Some(_) => filter_to_int(max_level),
+        None => 0,
+    };
+    MAX_LEVEL.store(max_level as usize, Ordering::Relaxed);
+    *SINK.write().unwrap() = sink;
+}
+
+/// The number a level is passed over the FFI as: 1 for error through 5 for trace.
+pub fn level_to_int(level: Level) -> i32 {
+    match level {
+        Level::ERROR => 1,
+        Level::WARN => 2,
+        Level::INFO => 3,
+        Level::DEBUG => 4,
+        Level::TRACE => 5,
+    }
+}
+
+/// The inverse of [`level_to_int`] for filters.  0 or less turns logging off.
+pub fn level_filter_from_int(level: i32) -> LevelFilter {
+    match level {
+        i32::MIN..=0 => LevelFilter::OFF,
+        1 => LevelFilter::ERROR,
+        2 => LevelFilter::WARN,
+        3 => LevelFilter::INFO,
+        4 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+fn filter_to_int(filter: LevelFilter) -> i32 {
+    filter.into_level().map(level_to_int).unwrap_or(0)
+}
+
+fn max_level() -> LevelFilter {
+    level_filter_from_int(MAX_LEVEL.load(Ordering::Relaxed) as i32)
+}
+
+/// Whether a field or parameter called `name` holds something that mustn't be logged.
+pub fn is_secret_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ["token", "secret", "password", "verifier", "nonce", "assertion"]
+        .iter()
+        .any(|s| name.ends_with(s))
+        || [
+            "code",
+            "auth_code",
+            "device_code",
+            "id_token_hint",
+            "authorization",
+            "dpop",
+        ]
+        .contains(&name.as_str())
+}
+
+/// Whether `word` looks like a JWS or JWE compact serialization.
+fn is_jwt(word: &str) -> bool {
+    let parts = word.split('.').collect::<Vec<_>>();
+    (parts.len() == 3 || parts.len() == 5)
+        && parts[0].starts_with("eyJ")
+        && !parts[1].is_empty()
+        && parts
+            .iter()
+            .all(|p| p.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'))
+}
+
+/// Whether `word` is an HTTP authentication scheme, which comes before the credentials
+/// in an `Authorization` header.
+fn is_auth_scheme(word: &str) -> bool {
+    ["Bearer", "Basic", "DPoP"].iter().any(|s| word.eq_ignore_ascii_case(s))
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || "\"'`,;()[]{}<>".contains(c)
+}
+
+/// Redacts the values of secret `name=value` pairs in a word, as found in URLs and
+/// form bodies.
+fn redact_params(word: &str) -> Option<String> {
+    if !word.contains('=') {
+        return None;
+    }
+    let mut out = String::with_capacity(word.len());
+    let mut changed = false;
+    let mut rest = word;
+    loop {
+        let end = rest.find(['?', '&']).unwrap_or(rest.len());
+        let (pair, tail) = rest.split_at(end);
+        match pair.split_once('=') {
+            Some((name, value)) if !value.is_empty() && is_secret_name(name.rsplit('/').next().unwrap_or(name)) => {
+                out.push_str(name);
+                out.push('=');
+                out.push_str(REDACTED);
+                changed = true;
+            }
+            _ => out.push_str(pair),
+        }
+        match tail.chars().next() {
+            Some(c) => {
+                out.push(c);
+                rest = &tail[1..];
+            }
+            None => break,
+        }
+    }
+    if changed {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// What the word before the current one said about it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    Anything,
+    /// A bare secret name, as in `"refresh_token":`.  The value follows a `:` or `=`.
+    Assignment,
+    /// The current word is a secret's value.
+    Value,
+}
+
+/// Masks secrets in free text: JWTs, secret URL or form parameters, and the values
+/// assigned to secret names, as in `"refresh_token":"..."` or `code: ...`.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    fn word(word: &str, out: &mut String, expect: &mut Expect) -> bool {
+        match *expect {
+            // as in `Authorization: Bearer ...`
+            Expect::Value if is_auth_scheme(word) => {
+                out.push_str(word);
+                return false;
+            }
+            Expect::Value => {
+                *expect = Expect::Anything;
+                out.push_str(REDACTED);
+                return true;
+            }
+            Expect::Assignment if word == ":" || word == "=" => {
+                *expect = Expect::Value;
+                out.push_str(word);
+                return false;
+            }
+            _ => *expect = Expect::Anything,
+        }
+
+        if let Some(name) = word.strip_suffix([':', '=']) {
+            if is_secret_name(name) {
+                *expect = Expect::Value;
+            }
+        } else if is_secret_name(word) {
+            *expect = Expect::Assignment;
+        }
+
+        if is_jwt(word) {
+            out.push_str(REDACTED);
+            true
+        } else if let Some(w) = redact_params(word) {
+            out.push_str(&w);
+            true
+        } else {
+            out.push_str(word);
+            false
+        }
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut changed = false;
+    let mut expect = Expect::Anything;
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if is_separator(c) {
+            if let Some(s) = start.take() {
+                changed |= word(&text[s..i], &mut out, &mut expect);
+            }
+            if c.is_whitespace() && c != ' ' {
+                // a new line or field ends the assignment
+                expect = Expect::Anything;
+            }
+            out.push(c);
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        changed |= word(&text[s..], &mut out, &mut expect);
+    }
+
+    if changed {
+        Cow::Owned(out)
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// A record's message and its other fields, formatted as ` name=value`.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: String,
+}
+
+impl Fields {
+    fn push(&mut self, name: &str, value: &str) {
+        if name == "message" {
+            self.message = redact(value).into_owned();
+        } else if is_secret_name(name) {
+            let _ = write!(self.fields, " {}={}", name, REDACTED);
+        } else {
+            let _ = write!(self.fields, " {}={}", name, redact(value));
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field.name(), &format!("{:?}", value));
+    }
+}
+
+/// The formatted fields of a span, kept in its extensions.
+struct SpanFields(String);
+
+struct HostLayer;
+
+impl<S> Layer<S> for HostLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // the level can change at any time
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        metadata.level() <= &max_level()
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            if let Some(f) = span.extensions_mut().get_mut::<SpanFields>() {
+                f.0.push_str(&fields.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut line = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                line.push_str(span.name());
+                if let Some(f) = span.extensions().get::<SpanFields>() {
+                    if !f.0.is_empty() {
+                        let _ = write!(line, "{{{}}}", f.0.trim_start());
+                    }
+                }
+                line.push_str(": ");
+            }
+        }
+
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        line.push_str(&fields.message);
+        line.push_str(&fields.fields);
+
+        let metadata = event.metadata();
+        if let Some(sink) = SINK.read().unwrap().as_ref() {
+            sink(*metadata.level(), metadata.target(), &line);
+        }
+    }
+}
@@ -24,6 +24,7 @@
 #include <thread>
 #include <mutex>
 #include <condition_variable>
+#include <atomic>
 
 #ifdef __FreeBSD__
 #include <sched.h>
@@ -131,6 +132,10 @@ using json = nlohmann::json;
 // How often to check for new multicast subscriptions on a tap device
 #define ZT_TAP_CHECK_MULTICAST_INTERVAL 5000
 
+// How often to refresh SSO login URLs, well inside the ten seconds before a pushed
+// authorization request's expiry at which zeroidc pushes it again
+#define ZT_SSO_AUTH_URL_CHECK_INTERVAL 2000
+
 // TCP fallback relay (run by ZeroTier, Inc. -- this will eventually go away)
 #ifndef ZT_SDK
 #define ZT_TCP_FALLBACK_RELAY "204.80.128.1/443"
@@ -286,7 +291,7 @@ public:
 		: _webPort(9993)
 		, _tap((EthernetTap *)0)
 #if ZT_SSO_ENABLED
-		, _idc(nullptr)
+		, _ssoLastEvent(-1)
 #endif
 	{
 		// Real defaults are in network 'up' code in network event handler
@@ -304,9 +309,10 @@ public:
 
 #if ZT_SSO_ENABLED
 		if (_idc) {
-			zeroidc::zeroidc_stop(_idc);
-			zeroidc::zeroidc_delete(_idc);
-			_idc = nullptr;
+			// the service loop may still hold the session for a moment
+			zeroidc::zeroidc_set_event_callback(_idc.get(), nullptr, nullptr);
+			zeroidc::zeroidc_stop(_idc.get());
+			_idc.reset();
 		}
 #endif
 	}
@@ -315,6 +321,10 @@ public:
 		_webPort = port;
 	}
 
+	void setHomePath(const std::string &homePath) {
+		_homePath = homePath;
+	}
+
 	void setTap(std::shared_ptr<EthernetTap> tap) {
 		this->_tap = tap;
 	}
@@ -387,33 +397,41 @@ public:
 				assert(_config.centralAuthURL != nullptr);
 				assert(_config.ssoProvider != nullptr);
 
-				_idc = zeroidc::zeroidc_new(
+				char nwid[17];
+				OSUtils::ztsnprintf(nwid, sizeof(nwid), "%.16llx", (unsigned long long)_config.nwid);
+
+#if ZT_DEBUG==1
+				zeroidc::zeroidc_set_log_callback(&NetworkState::ssoLogCallback, nullptr, 4);
+#else
+				zeroidc::zeroidc_set_log_callback(&NetworkState::ssoLogCallback, nullptr, 3);
+#endif
+				zeroidc::ZeroIDC *idc = zeroidc::zeroidc_new(
 					_config.issuerURL,
 					_config.ssoClientID,
 					_config.centralAuthURL,
 					_config.ssoProvider,
-					_webPort
+					_webPort,
+					nwid,
+					_homePath.empty() ? nullptr : _homePath.c_str()
 				);
 
-				if (_idc == nullptr) {
+				if (idc == nullptr) {
 					fprintf(stderr, "idc is null\n");
 					return;
 				}
+				_idc = std::shared_ptr<zeroidc::ZeroIDC>(idc, zeroidc::zeroidc_delete);
+
+				zeroidc::zeroidc_set_event_callback(_idc.get(), &NetworkState::ssoEventCallback, this);
 			}
 
 			zeroidc::zeroidc_set_nonce_and_csrf(
-				_idc,
+				_idc.get(),
 				_config.ssoState,
 				_config.ssoNonce
 			);
 
-			char* url = zeroidc::zeroidc_get_auth_url(_idc);
-			memcpy(_config.authenticationURL, url, strlen(url));
-			_config.authenticationURL[strlen(url)] = 0;
-			zeroidc::free_cstr(url);
-
-			if (zeroidc::zeroidc_is_running(_idc) && nwc->status == ZT_NETWORK_STATUS_AUTHENTICATION_REQUIRED) {
-				zeroidc::zeroidc_kick_refresh_thread(_idc);
+			if (zeroidc::zeroidc_is_running(_idc.get()) && nwc->status == ZT_NETWORK_STATUS_AUTHENTICATION_REQUIRED) {
+				zeroidc::zeroidc_kick_refresh_thread(_idc.get());
 			}
 #endif
 		}
@@ -432,36 +450,143 @@ public:
 	}
 
 
-	char* doTokenExchange(const char *code) {
-		char *ret = nullptr;
 #if ZT_SSO_ENABLED
+	zeroidc::ZeroIDCExchangeResult* doTokenExchange(const char *code) {
+		zeroidc::ZeroIDCExchangeResult *ret = nullptr;
 		if (_idc == nullptr) {
 			fprintf(stderr, "ainfo or idc null\n");
 			return ret;
 		}
 
-		ret = zeroidc::zeroidc_token_exchange(_idc, code);
+		ret = zeroidc::zeroidc_token_exchange(_idc.get(), code);
+		if (ret != nullptr && ret->code == zeroidc::ZeroIDCErrorCode_Ok) {
+			_ssoLastEvent = -1;
+		}
 		zeroidc::zeroidc_set_nonce_and_csrf(
-			_idc,
+			_idc.get(),
 			_config.ssoState,
 			_config.ssoNonce
 		);
+		return ret;
+	}
+#endif
 
-		char* url = zeroidc::zeroidc_get_auth_url(_idc);
-		memcpy(_config.authenticationURL, url, strlen(url));
-		_config.authenticationURL[strlen(url)] = 0;
-		zeroidc::free_cstr(url);
+	// The URL to log in at.  With SSO v1 it's whatever the service loop last got from
+	// zeroidc (see ssoAuthURL()), since a pushed authorization request's URL is only
+	// good for a minute or so.
+	std::string authenticationURL() const {
+#if ZT_SSO_ENABLED
+		if ((_idc != nullptr)&&(!_ssoAuthURL.empty())) {
+			return _ssoAuthURL;
+		}
 #endif
-		return ret;
+		return std::string(_config.authenticationURL);
+	}
+
+	// Ends the SSO session on the way out of the network.  Logging out makes blocking
+	// requests to the IdP, so the session is handed off to a thread of its own rather
+	// than holding up the caller, who usually has _nets_m locked.
+	void ssoLogout() {
+#if ZT_SSO_ENABLED
+		if (_idc == nullptr) {
+			return;
+		}
+		std::shared_ptr<zeroidc::ZeroIDC> idc(std::move(_idc));
+		_ssoAuthURL.clear();
+		// this NetworkState is about to go away
+		zeroidc::zeroidc_set_event_callback(idc.get(), nullptr, nullptr);
+		const uint64_t nwid = _config.nwid;
+		std::thread([idc, nwid]() {
+			if (!zeroidc::zeroidc_logout(idc.get())) {
+				fprintf(stderr, "SSO logout for network %.16llx did not complete cleanly\n", (unsigned long long)nwid);
+			}
+		}).detach();
+#endif
+	}
+
+#if ZT_SSO_ENABLED
+	// The session, for asking zeroidc things that may block without _nets_m locked
+	std::shared_ptr<zeroidc::ZeroIDC> ssoSession() const {
+		return _idc;
+	}
+
+	// Fetches the URL to log in at from a session got from ssoSession().  Pushing the
+	// authorization request when it's about to expire makes a blocking request to the
+	// IdP, so this must be called without _nets_m locked.
+	static std::string ssoAuthURL(zeroidc::ZeroIDC *idc) {
+		std::string s;
+		char *url = zeroidc::zeroidc_get_auth_url(idc);
+		if (url != nullptr) {
+			s = url;
+			zeroidc::free_cstr(url);
+		}
+		return s;
+	}
+
+	void setSsoAuthURL(const std::string &url) {
+		_ssoAuthURL = url;
+	}
+
+	// Last event reported by zeroidc since the last successful login, or -1
+	int ssoLastEvent() const {
+		return _ssoLastEvent;
+	}
+
+	// State, last error, expiry etc of the SSO session as reported by zeroidc
+	nlohmann::json ssoStatus() {
+		nlohmann::json status;
+		if (_idc == nullptr) {
+			return status;
+		}
+		char *s = zeroidc::zeroidc_get_status_json(_idc.get());
+		if (s != nullptr) {
+			status = nlohmann::json::parse(s, nullptr, false);
+			zeroidc::free_cstr(s);
+			if (status.is_discarded()) {
+				status = nlohmann::json();
+			}
+		}
+		return status;
+	}
+
+	// Who the user is logged in as: subject, email, name and configured extra claims
+	nlohmann::json ssoClaims() {
+		nlohmann::json claims;
+		if (_idc == nullptr) {
+			return claims;
+		}
+		char *s = zeroidc::zeroidc_get_claims_json(_idc.get());
+		if (s != nullptr) {
+			claims = nlohmann::json::parse(s, nullptr, false);
+			zeroidc::free_cstr(s);
+			if (claims.is_discarded()) {
+				claims = nlohmann::json();
+			}
+		}
+		return claims;
+	}
+
+	// Called on one of zeroidc's threads
+	static void ssoEventCallback(void *ctx, zeroidc::ZeroIDCEvent event, const char *detail) {
+		NetworkState *ns = reinterpret_cast<NetworkState *>(ctx);
+		ns->_ssoLastEvent = (int)event;
+		fprintf(stderr, "SSO event %d for network %.16llx: %s\n", (int)event, (unsigned long long)ns->_config.nwid, detail);
 	}
 
+	// Log records from zeroidc, with tokens already redacted. May be called from any thread.
+	static void ssoLogCallback(void *ctx, int level, const char *target, const char *message) {
+		static const char *levels[] = { "OFF", "ERROR", "WARN", "INFO", "DEBUG", "TRACE" };
+		fprintf(stderr, "%s %s: %s\n", (level >= 1 && level <= 5) ? levels[level] : "?", target, message);
+	}
+#endif
+
 	uint64_t getExpiryTime() {
 #if ZT_SSO_ENABLED
 		if (_idc == nullptr) {
 			fprintf(stderr, "idc is null\n");
 			return 0;
 		}
-		return zeroidc::zeroidc_get_exp_time(_idc);
+		return zeroidc::zeroidc_get_exp_time(_idc.get());
 #else
 		return 0;
 #endif
@@ -469,13 +594,16 @@ public:
 
 private:
 	unsigned int _webPort;
+	std::string _homePath;
 	std::shared_ptr<EthernetTap> _tap;
 	ZT_VirtualNetworkConfig _config; // memcpy() of raw config from core
 	std::vector<InetAddress> _managedIps;
 	std::map< InetAddress, SharedPtr<ManagedRoute> > _managedRoutes;
 	OneService::NetworkSettings _settings;
 #if ZT_SSO_ENABLED
-	zeroidc::ZeroIDC *_idc;
+	std::shared_ptr<zeroidc::ZeroIDC> _idc;
+	std::string _ssoAuthURL;
+	std::atomic<int> _ssoLastEvent;
 #endif
 };
 
@@ -588,11 +716,24 @@ static void _networkToJson(nlohmann::json &nj,NetworkState &ns)
 	}
 	nj["dns"] = m;
 	if (ns.config().ssoEnabled) {
-		const char* authURL = ns.config().authenticationURL;
-		//fprintf(stderr, "Auth URL: %s\n", authURL);
-		nj["authenticationURL"] = authURL;
+		nj["authenticationURL"] = ns.authenticationURL();
 		nj["authenticationExpiryTime"] = (ns.getExpiryTime()*1000);
 		nj["ssoEnabled"] = ns.config().ssoEnabled;
+#if ZT_SSO_ENABLED
+		switch(ns.ssoLastEvent()) {
+			case zeroidc::ZeroIDCEvent_RefreshSucceeded: nj["ssoLastEvent"] = "REFRESH_SUCCEEDED"; break;
+			case zeroidc::ZeroIDCEvent_RefreshFailed: nj["ssoLastEvent"] = "REFRESH_FAILED"; break;
+			case zeroidc::ZeroIDCEvent_AboutToExpire: nj["ssoLastEvent"] = "ABOUT_TO_EXPIRE"; break;
+			case zeroidc::ZeroIDCEvent_Expired: nj["ssoLastEvent"] = "EXPIRED"; break;
+			case zeroidc::ZeroIDCEvent_ReauthRequired: nj["ssoLastEvent"] = "REAUTH_REQUIRED"; break;
+			case zeroidc::ZeroIDCEvent_LoginSucceeded: nj["ssoLastEvent"] = "LOGIN_SUCCEEDED"; break;
+			case zeroidc::ZeroIDCEvent_LoginFailed: nj["ssoLastEvent"] = "LOGIN_FAILED"; break;
+			default: nj["ssoLastEvent"] = nullptr; break;
+		}
+		nj["ssoReauthRequired"] = (ns.ssoLastEvent() == zeroidc::ZeroIDCEvent_ReauthRequired);
+		nj["ssoStatus"] = ns.ssoStatus();
+		nj["ssoClaims"] = ns.ssoClaims();
+#endif
 	}
 }
 
@@ -797,6 +938,10 @@ public:
 	std::thread _serverThreadV6;
 	bool _serverThreadRunning;
 	bool _serverThreadRunningV6;
+#if ZT_SSO_ENABLED
+	std::thread _ssoAuthURLThread;
+	std::atomic<bool> _ssoAuthURLThreadRunning;
+#endif
 
 	BlockingQueue<PacketRecord *> _rxPacketQueue;
 	std::vector<PacketRecord *> _rxPacketVector;
@@ -909,6 +1054,10 @@ public:
 		,_serverThreadV6()
 		,_serverThreadRunning(false)
 		,_serverThreadRunningV6(false)
+#if ZT_SSO_ENABLED
+		,_ssoAuthURLThread()
+		,_ssoAuthURLThreadRunning(false)
+#endif
 		,_forceTcpRelay(false)
 		,_primaryPort(port)
 		,_udpPortPickerCounter(0)
@@ -974,6 +1123,11 @@ public:
 		if (_serverThreadRunningV6) {
 			_serverThreadV6.join();
 		}
+#if ZT_SSO_ENABLED
+		if (_ssoAuthURLThread.joinable()) {
+			_ssoAuthURLThread.join();
+		}
+#endif
 		_rxPacketVector_m.lock();
 		while (!_rxPacketVector.empty()) {
 			delete _rxPacketVector.back();
@@ -1165,6 +1319,9 @@ public:
 			int64_t lastBindRefresh = 0;
 			int64_t lastUpdateCheck = clockShouldBe;
 			int64_t lastCleanedPeersDb = 0;
+#if ZT_SSO_ENABLED
+			int64_t lastSsoAuthURLCheck = 0;
+#endif
 			int64_t lastLocalConfFileCheck = OSUtils::now();
 			int64_t lastOnline = lastLocalConfFileCheck;
 			for(;;) {
@@ -1297,6 +1454,39 @@ public:
 					}
 				}
 
+#if ZT_SSO_ENABLED
+				// Refresh SSO login URLs.  That may push an authorization request to the IdP,
+				// which blocks, so it's done on a thread of its own without _nets_m locked.
+				if (((now - lastSsoAuthURLCheck) >= ZT_SSO_AUTH_URL_CHECK_INTERVAL) && (!_ssoAuthURLThreadRunning)) {
+					lastSsoAuthURLCheck = now;
+					if (_ssoAuthURLThread.joinable())
+						_ssoAuthURLThread.join();
+					std::vector< std::pair< uint64_t,std::shared_ptr<zeroidc::ZeroIDC> > > sessions;
+					{
+						Mutex::Lock _l(_nets_m);
+						for(std::map<uint64_t,NetworkState>::const_iterator n(_nets.begin());n!=_nets.end();++n) {
+							std::shared_ptr<zeroidc::ZeroIDC> idc(n->second.ssoSession());
+							if (idc)
+								sessions.push_back(std::pair< uint64_t,std::shared_ptr<zeroidc::ZeroIDC> >(n->first,idc));
+						}
+					}
+					if (!sessions.empty()) {
+						_ssoAuthURLThreadRunning = true;
+						_ssoAuthURLThread = std::thread([this,sessions]() {
+							for(std::vector< std::pair< uint64_t,std::shared_ptr<zeroidc::ZeroIDC> > >::const_iterator s(sessions.begin());s!=sessions.end();++s) {
+								const std::string url(NetworkState::ssoAuthURL(s->second.get()));
+								Mutex::Lock _l(_nets_m);
+								std::map<uint64_t,NetworkState>::iterator n(_nets.find(s->first));
+								// unless the network was left or its session replaced meanwhile
+								if ((n != _nets.end())&&(n->second.ssoSession() == s->second))
+									n->second.setSsoAuthURL(url);
+							}
+							_ssoAuthURLThreadRunning = false;
+						});
+					}
+				}
+#endif
+
 				// Clean peers.d periodically
 				if ((now - lastCleanedPeersDb) >= 3600000) {
 					lastCleanedPeersDb = now;
@@ -1371,6 +1561,10 @@ public:
 				_phy.close((*_tcpConnections.begin())->sock);
 		} catch ( ... ) {}
 
+#if ZT_SSO_ENABLED
+		if (_ssoAuthURLThread.joinable())
+			_ssoAuthURLThread.join();
+#endif
 		{
 			Mutex::Lock _l(_nets_m);
 			_nets.clear();
@@ -2272,31 +2466,37 @@ public:
             if (_nets.find(id) != _nets.end()) {
                 NetworkState& ns = _nets[id];
                 std::string code = req.get_param_value("code");
-                char *ret = ns.doTokenExchange(code.c_str());
-                json ssoResult = json::parse(ret);
-                if (ssoResult.is_object()) {
-                    if (ssoResult.contains("errorMessage")) {
-                        outData["isError"] = true;
-                        outData["messageText"] = ssoResult["errorMessage"];
-                        responseBody = inja::render(htmlTemplate, outData);
-                        res.set_content(responseBody, responseContentType);
-                        res.status = 500;
-                    } else {
-                        outData["isError"] = false;
-                        outData["messageText"] = "Authentication Successful. You may now access the network.";
-                        responseBody = inja::render(htmlTemplate, outData);
-                        res.set_content(responseBody, responseContentType);
-                    }
-                } else {
-                    // not an object? We got a problem
+                zeroidc::ZeroIDCExchangeResult *ret = ns.doTokenExchange(code.c_str());
+                if (ret == nullptr) {
                     outData["isError"] = true;
                     outData["messageText"] = "ERROR: Unkown SSO response. Please contact your administrator.";
                     responseBody = inja::render(htmlTemplate, outData);
                     res.set_content(responseBody, responseContentType);
                     res.status = 500;
+                    return;
                 }
 
-                zeroidc::free_cstr(ret);
+                if (ret->code != zeroidc::ZeroIDCErrorCode_Ok) {
+                    outData["isError"] = true;
+                    outData["errorCode"] = (int)ret->code;
+                    outData["messageText"] = std::string(ret->message ? ret->message : "");
+                    responseBody = inja::render(htmlTemplate, outData);
+                    res.set_content(responseBody, responseContentType);
+                    if (ret->code == zeroidc::ZeroIDCErrorCode_LicenseRequired) {
+                        res.status = 402;
+                    } else if (ret->code == zeroidc::ZeroIDCErrorCode_PolicyRejected) {
+                        res.status = 403;
+                    } else {
+                        res.status = 500;
+                    }
+                } else {
+                    outData["isError"] = false;
+                    outData["messageText"] = "Authentication Successful. You may now access the network.";
+                    responseBody = inja::render(htmlTemplate, outData);
+                    res.set_content(responseBody, responseContentType);
+                }
+
+                zeroidc::zeroidc_exchange_result_free(ret);
             }
         };
         _controlPlane.Get(ssoPath, ssoGet);
@@ -3170,6 +3370,7 @@ public:
 		Mutex::Lock _l(_nets_m);
 		NetworkState &n = _nets[nwid];
 		n.setWebPort(_primaryPort);
+		n.setHomePath(_homePath);
 
 		switch (op) {
 			case ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_UP:
@@ -3268,6 +3469,10 @@ public:
 
 			case ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_DOWN:
 			case ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_DESTROY:
+				if (op == ZT_VIRTUAL_NETWORK_CONFIG_OPERATION_DESTROY) {
+					// leaving the network, so end the SSO session with the IdP too
+					n.ssoLogout();
+				}
 				if (n.tap()) { // sanity check
 #if defined(__WINDOWS__) && !defined(ZT_SDK)
 					std::string winInstanceId(((WindowsEthernetTap *)(n.tap().get()))->instanceId());